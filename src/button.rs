@@ -1,13 +1,26 @@
-use rtic_core::prelude::*;
-use stm32f4xx_hal::{
-  prelude::*,
-  gpio::ExtiPin,
+use rtic::mutex_prelude::*;
+use stm32f4xx_hal::gpio::ExtiPin;
+use heapless::{
+  Vec,
+  consts::U4,
 };
-use rtic::cyccnt::{Instant, U32Ext};
+
+pub use stm32f4_core::button::{
+  Message,
+  Gesture,
+};
+use stm32f4_core::button::{
+  State,
+  Action,
+  Debouncer,
+  Recognizer,
+  Reaction,
+};
+use stm32f4_core::time::Millis;
 
 use crate::util;
+use crate::util::{Requester, Scheduled};
 use crate::util::debugger;
-use crate::heartbeat;
 use crate::app;
 use crate::app::{
   button_mb_app,
@@ -16,86 +29,98 @@ use crate::app::{
   Task,
 };
 
-pub const SAMPLE_SIZE: usize = 10;
-const SAMPLE_THRESHOLD: usize = 5;
-
-#[derive(Debug)]
-pub enum Message {
-  ButtonPressed,
-  ButtonNotPressed
-}
+// between two samples of the pin
+const SAMPLE_PERIOD: util::Duration = util::millis(10);
 
 pub struct Data<T> {
   pub button: T,
   state: State,
-  sample_cnt: usize,
-  sample_data: [bool; SAMPLE_SIZE] 
-}
-
-enum Action {
-  DoNothing,
-  Schedule
-}
-
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum State {
-  NotPressed,
-  Pressed,
+  debouncer: Debouncer,
+  // the pin as the last full window of samples voted, true while pressed
+  down: bool,
+  recognizer: Recognizer,
+  // the timer the recognizer asked for last
+  timer: Option<Scheduled>,
+  // get the gestures, with the id they subscribed with
+  subscribers: Vec<Requester, U4>,
 }
 
 
 pub fn button_mb(cx: button_mb_app::Context, msg: MessagePacket) {
 
-  let mut button_data = cx.resources.button;
+  let mut button_data = cx.shared.button;
+  let requester = msg.requester();
 
-  (button_data).lock(|button_data| {
+  let gesture = (button_data).lock(|button_data| {
 
-    match msg.msg { 
-      app::Message::Button(x) => {
-        
-        let action;
-        (button_data.state, action) = button_data.state.next(&x);
+    if let app::Message::Button(x) = msg.msg {
 
-        match action {
-          Action::Schedule => {
-            match button_app::schedule(Instant::now()) {
-              Ok(_) => (),
-              Err(_) => {
-                debugger::print(format_args!("Button is already scheduled"));
-              }
-            }
+      match x {
+        Message::Subscribe => {
+          if button_data.subscribers.push(requester).is_err() {
+            debugger::print(format_args!("No room for another button subscriber"));
+          }
+        }
+        Message::Configure(timing) => {
+          button_data.recognizer.timing = timing;
+        }
+        Message::Timer(timer, generation) => {
+          let reaction = button_data.recognizer.timer(timer, generation);
+          button_data.react(reaction);
+          return reaction.gesture;
+        }
+        x => {
+          let action;
+          (button_data.state, action) = button_data.state.next(&x);
+
+          if action == Action::Schedule && button_app::spawn().is_err() {
+            debugger::print(format_args!("Button is already scheduled"));
           }
-          _ => ()
         }
       }
-      _ => ()
     }
+    None
   });
+
+  // a no-op unless built with the `trace` feature. the button is shared with
+  // its interrupt, the dump must not run under its lock
+  if gesture == Some(Gesture::LongPress) {
+    util::trace::dump();
+  }
 }
 
 pub fn button(cx: button_app::Context) {
 
-    let button_data = cx.resources.button;
-    let exti = cx.resources.exti;
+    let button_data = cx.shared.button;
+    let exti = cx.shared.exti;
 
     (button_data, exti).lock(|button_data, exti| {
 
-      if button_data.sample_cnt < SAMPLE_SIZE {
+      if !button_data.debouncer.is_full() {
         // continue scheduling itself recursively while sampling the button pin
-        button_data.sample_data[button_data.sample_cnt] = button_data.button.is_high().unwrap();
-        button_data.sample_cnt += 1;
-        button_app::schedule(Instant::now() + util::convert_us_to_cycles(10_000).cycles()).unwrap();
-      } else {
-        // check to see if we have enough correct values to trigger a button press
-        let sample_cnt = button_data.sample_data.iter()
-          .filter(|&x| *x == true)
-          .count();
+        button_data.debouncer.record(button_data.button.is_high());
+        button_app::spawn_after(SAMPLE_PERIOD).unwrap();
+        return;
+      }
 
-        if sample_cnt > SAMPLE_THRESHOLD {
-          util::send_message(Task::Spi1, &Task::Heartbeat, app::Message::Heartbeat(heartbeat::Message::Toggle)).unwrap();
-        }
+      let down = button_data.debouncer.is_pressed();
+      button_data.debouncer.reset();
+
+      if down != button_data.down {
+        button_data.down = down;
+        let reaction = if down {
+          button_data.recognizer.press()
+        } else {
+          button_data.recognizer.release()
+        };
+        button_data.react(reaction);
+      }
 
-        button_data.sample_cnt = 0;
+      // the pin is watched until the gesture is over, the interrupt only
+      // catches the first press
+      if button_data.down || button_data.recognizer.is_busy() {
+        button_app::spawn_after(SAMPLE_PERIOD).unwrap();
+      } else {
         (button_data.state, ..) = button_data.state.next(&Message::ButtonNotPressed);
         button_data.button.enable_interrupt(exti);
       }
@@ -103,29 +128,38 @@ pub fn button(cx: button_app::Context) {
   }
 
 
-impl State {
-  pub fn next(self, msg: &Message) -> (State, Action) {
-    match (self, msg) {
-      (State::NotPressed, Message::ButtonPressed) => {
-        (State::Pressed, Action::Schedule)
-      }
-      (State::Pressed, Message::ButtonNotPressed) => {
-        (State::NotPressed, Action::DoNothing)
-      }
-      (s, _m) => {
-        (s, Action::DoNothing)
-      }
-    }
-  }
-}
-
 impl<T> Data<T> {
   pub fn new(button: T) -> Self {
     Data {
       button,
       state: State::NotPressed,
-      sample_cnt: 0,
-      sample_data: [false; SAMPLE_SIZE]
+      debouncer: Debouncer::new(),
+      down: false,
+      recognizer: Recognizer::new(),
+      timer: None,
+      subscribers: Vec::new(),
     }
   }
-}
\ No newline at end of file
+
+  // timers left behind by the recognizer are ignored when they arrive, the
+  // one replaced here is only cancelled to keep it out of the mailbox
+  fn react(&mut self, reaction: Reaction) {
+    if let Some(gesture) = reaction.gesture {
+      for subscriber in self.subscribers.iter() {
+        let msg = app::Message::Button(Message::Gesture(gesture));
+        util::reply(Task::Button, subscriber, msg).unwrap_or_else(util::log_error);
+      }
+    }
+
+    if let Some((timer, ms)) = reaction.timer {
+      if let Some(pending) = self.timer.take() {
+        pending.cancel();
+      }
+
+      let msg = app::Message::Button(Message::Timer(timer, self.recognizer.generation()));
+      self.timer = util::schedule_message(Task::Button, &Task::Button, msg, Millis(ms))
+        .map_err(util::log_error)
+        .ok();
+    }
+  }
+}
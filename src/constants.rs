@@ -1 +1,79 @@
-pub const CPU_FREQ: u32 = 168_000_000;
\ No newline at end of file
+//! Clock tree of the STM32F4 Discovery board. `init` configures the RCC from
+//! these and checks the HAL reached them, everything that converts time to
+//! cycles reads them, so they can't drift apart. Combinations the part can't
+//! run are rejected at compile time.
+
+/// Crystal on the board
+pub const HSE: u32 = 8_000_000;
+
+/// Out of the main PLL, `init` asks the HAL for it and checks it got there
+pub const SYSCLK: u32 = 168_000_000;
+
+// main PLL: HSE / M feeds the VCO, which runs at N times that and is divided
+// by P for SYSCLK. the HAL works the dividers out from SYSCLK by itself, these
+// follow its rules so they are the ones it ends up with
+pub const PLL_P: u32 = min(8, (432_000_000 / SYSCLK) & !1);
+pub const PLL_M: u32 = pll_m(PLL_P);
+pub const PLL_N: u32 = SYSCLK * PLL_P / (HSE / PLL_M);
+
+// bus prescalers
+pub const AHB_DIV: u32 = 1;
+pub const APB1_DIV: u32 = 4;
+pub const APB2_DIV: u32 = 2;
+
+pub const HCLK: u32 = SYSCLK / AHB_DIV;
+pub const PCLK1: u32 = HCLK / APB1_DIV;
+pub const PCLK2: u32 = HCLK / APB2_DIV;
+
+/// Clock of the timers on APB1 (TIM2 to TIM7, TIM12 to TIM14). Timers run at
+/// twice their bus clock when the bus is divided down.
+pub const TIMCLK1: u32 = if APB1_DIV == 1 { PCLK1 } else { PCLK1 * 2 };
+
+/// Core clock, what the cycle counter and SysTick count
+pub const CPU_FREQ: u32 = HCLK;
+
+const fn is_prescaler(div: u32, max: u32) -> bool {
+  div.is_power_of_two() && div <= max
+}
+
+const fn min(a: u32, b: u32) -> u32 {
+  if a < b { a } else { b }
+}
+
+// the HAL takes the lowest M for a 1 to 2MHz PLL input whose VCO comes
+// closest to SYSCLK * P from below
+const fn pll_m(pll_p: u32) -> u32 {
+  let target = SYSCLK * pll_p;
+  let mut m = HSE.div_ceil(2_000_000);
+  let mut best = m;
+  while m <= HSE / 1_000_000 {
+    let vco_in = HSE / m;
+    if target % vco_in < target % (HSE / best) {
+      best = m;
+    }
+    m += 1;
+  }
+  best
+}
+
+// limits from the STM32F407 datasheet
+const _: () = {
+  assert!(HSE >= 4_000_000 && HSE <= 26_000_000, "HSE has to be 4 to 26MHz");
+  assert!(SYSCLK >= 24_000_000 && SYSCLK <= 168_000_000, "the PLL gives 24 to 168MHz");
+  assert!(HSE / PLL_M >= 1_000_000 && HSE / PLL_M <= 2_000_000, "the PLL input has to be 1 to 2MHz");
+  assert!(PLL_N >= 50 && PLL_N <= 432, "PLL_N has to be 50 to 432");
+  assert!(HSE / PLL_M * PLL_N >= 100_000_000 && HSE / PLL_M * PLL_N <= 432_000_000, "the VCO has to run at 100 to 432MHz");
+  assert!(PLL_P == 2 || PLL_P == 4 || PLL_P == 6 || PLL_P == 8, "PLL_P has to be 2, 4, 6 or 8");
+  // the HAL would settle for the nearest SYSCLK below
+  assert!(HSE / PLL_M * PLL_N / PLL_P == SYSCLK, "the PLL can't make SYSCLK out of HSE");
+
+  // HPRE skips 32
+  assert!(is_prescaler(AHB_DIV, 512) && AHB_DIV != 32, "AHB_DIV has to be a power of two up to 512, but not 32");
+  assert!(is_prescaler(APB1_DIV, 16), "APB1_DIV has to be a power of two up to 16");
+  assert!(is_prescaler(APB2_DIV, 16), "APB2_DIV has to be a power of two up to 16");
+  assert!(PCLK1 <= 42_000_000, "APB1 is 42MHz at most");
+  assert!(PCLK2 <= 84_000_000, "APB2 is 84MHz at most");
+
+  // time::Micros converts to whole cycles and back without drift
+  assert!(CPU_FREQ.is_multiple_of(1_000_000), "the core clock has to be a whole number of MHz");
+};
@@ -1,142 +1,143 @@
-use stm32f4xx_hal::{
-  prelude::*,
-  pwm::PwmChannels,
-  pwm::C2,
-  stm32::TIM4,
+use rtic::mutex_prelude::*;
+
+pub use stm32f4_core::heartbeat::Message;
+pub use stm32f4_core::health::Status;
+use stm32f4_core::heartbeat::{
+  State,
+  Action,
+  Breathing,
 };
-
-use rtic::cyccnt::{Instant, U32Ext};
-use rtic_core::prelude::*;
+use stm32f4_core::health::{
+  Monitor,
+  Indication,
+};
+use stm32f4_core::pattern::Pattern;
+use stm32f4_core::time::Millis;
 
 use crate::util;
+use crate::leds::Led;
+use crate::button;
+use crate::button::Gesture;
 use crate::util::debugger;
 use crate::app;
 use crate::app::{
   heartbeat_mb_app,
   heartbeat_app,
   MessagePacket,
+  Task,
 };
 
-pub struct Data<T, U> {
-  led: PwmChannels<T, U>,
+// time between two updates of the breath, blink codes are updated when they
+// change but at least every MAX_HOLD_MS so a new one shows without delay
+const STEP_MS: u32 = 10;
+const MAX_HOLD_MS: u32 = 100;
+
+/// Breathes on the orange LED while all is well, blinks a code otherwise
+pub struct Data {
   state: State,
+  breathing: Breathing,
+  monitor: Monitor,
+  // the pattern asked for with `Message::Play`
+  played: Option<&'static Pattern>,
+  shown: Indication,
 }
 
-#[derive(Debug)]
-pub enum Message {
-  TurnOff,
-  TurnOn,
-  Toggle
-}
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum State {
-  Off,
-  On,
-}
+pub fn heartbeat_mb(cx: heartbeat_mb_app::Context, msg: MessagePacket) {
 
-enum Action {
-  DoNothing,
-  Schedule
-}
+  let mut hb_data = cx.shared.heartbeat;
 
+  let msg = match msg.msg {
+    app::Message::Heartbeat(x) => Some(x),
+    // subscribed to the button in `init`
+    app::Message::Button(button::Message::Gesture(Gesture::ShortPress)) => Some(Message::Toggle),
+    _ => None,
+  };
 
-pub fn heartbeat_mb(cx: heartbeat_mb_app::Context, msg: MessagePacket) {
+  (hb_data).lock(|hb_data| {
 
-  let mut hb_data = cx.resources.heartbeat;
+    if let Some(x) = msg {
 
-  (hb_data).lock(|hb_data| {
+      let action;
+      (hb_data.state, action) = hb_data.state.next(&x);
+      hb_data.breathing.apply(&x);
 
-    match msg.msg { 
-      app::Message::Heartbeat(x) => {
-        
-        let action;
-        (hb_data.state, action) = hb_data.state.next(&x);
-
-        match action {
-          Action::Schedule => {
-            match heartbeat_app::schedule(Instant::now(), true) {
-              Ok(_) => (),
-              Err(_) => {
-                debugger::print(format_args!("Heartbeat is already scheduled"));
-              }
-            }
-          }
-          _ => ()
-        }
+      match x {
+        Message::ReportStatus(status) => hb_data.monitor.report(status),
+        Message::Play(pattern) => hb_data.played = Some(pattern),
+        _ => (),
+      }
+
+      if action == Action::Schedule && heartbeat_app::spawn(app::monotonics::now(), 0).is_err() {
+        debugger::print(format_args!("Heartbeat is already scheduled"));
       }
-      _ => ()
     }
   });
 }
 
-pub fn heartbeat(cx: heartbeat_app::Context, mut increment: bool) {
+pub fn heartbeat(cx: heartbeat_app::Context, due: util::Instant, elapsed_ms: u32) {
 
-  let mut hb_data = cx.resources.heartbeat;
-  let scheduled = cx.scheduled;
-
-  (hb_data).lock(|hb_data| {
-    adjust_duty_cycle(&mut hb_data.led, &mut increment);
+  let hb_data = cx.shared.heartbeat;
+  let leds = cx.shared.leds;
 
+  (hb_data, leds).lock(|hb_data, leds| {
     if hb_data.state == State::On {
-      heartbeat_app::schedule(scheduled + util::convert_us_to_cycles(30_000).cycles(), increment).unwrap();
+      // read here rather than reported, a report of a drop could be dropped itself
+      hb_data.monitor.report(Status::MailboxDrops(util::lost()));
+
+      // a new code starts from the beginning
+      let indication = hb_data.showing();
+      let elapsed_ms = if indication == hb_data.shown { elapsed_ms } else { 0 };
+      hb_data.shown = indication;
+
+      match indication.at(elapsed_ms, &hb_data.breathing) {
+        Some(level) => {
+          leds.set_brightness(Led::Orange, level.brightness);
+
+          let hold_ms = level.remaining_ms.clamp(STEP_MS, MAX_HOLD_MS);
+          hb_data.monitor.tick(hold_ms);
+
+          // never more than MAX_HOLD_MS, it fits
+          let next = due + util::duration(Millis(hold_ms)).unwrap();
+          let elapsed_ms = indication.wrap(elapsed_ms.saturating_add(hold_ms), &hb_data.breathing);
+          heartbeat_app::spawn_at(next, next, elapsed_ms).unwrap();
+        }
+        None => {
+          // the pattern has played out, back to what it covered
+          hb_data.played = None;
+          heartbeat_app::spawn(due, 0).unwrap();
+        }
+      }
     } else {
-      hb_data.led.disable();
-      hb_data.led.set_duty(0);
+      hb_data.played = None;
+      leds.set_brightness(Led::Orange, 0);
     }
   });
 }
 
-fn adjust_duty_cycle(led: &mut PwmChannels<TIM4, C2>, increment: &mut bool) {
-  // max duty is 4200 so 4200 / 100 = 42 total steps
-  const STEP_SIZE: u16 = 100;
 
-  let curr_duty = led.get_duty();
-
-  // see if direction should change
-  if curr_duty == 0 {
-    *increment = true;
-  } else if curr_duty == led.get_max_duty() {
-    *increment = false;
-  }
-
-  led.disable();
-  if *increment {
-    led.set_duty(curr_duty + STEP_SIZE);
-  } else {
-    led.set_duty(curr_duty - STEP_SIZE);
+impl Data {
+  pub fn new() -> Self {
+    Data {
+      state: State::Off,
+      breathing: Breathing::new(),
+      monitor: Monitor::new(),
+      played: None,
+      shown: Indication::Breathe,
+    }
   }
-  led.enable();
-}
 
-
-impl State {
-  pub fn next(self, msg: &Message) -> (State, Action) {
-    match (self, msg) {
-      (State::Off, Message::TurnOn) => {
-        (State::On, Action::Schedule)
-      }
-      (State::Off, Message::Toggle) => {
-        (State::On, Action::Schedule)
-      }
-      (State::On, Message::TurnOff) => {
-        (State::Off, Action::DoNothing)
-      }
-      (State::On, Message::Toggle) => {
-        (State::Off, Action::DoNothing)
-      }
-      (s, _m) => {
-        (s, Action::DoNothing)
-      }
+  // blink codes for the health go before a played pattern
+  fn showing(&self) -> Indication {
+    match (self.monitor.indication(), self.played) {
+      (Indication::Breathe, Some(pattern)) => Indication::Blink(pattern),
+      (indication, _) => indication,
     }
   }
 }
 
-impl<T, U> Data<T, U> {
-  pub fn new(led: PwmChannels<T, U>) -> Self {
-    Data {
-      led,
-      state: State::Off
-    } 
-  }
-}
\ No newline at end of file
+/// For the tasks the heartbeat keeps an eye on, whenever one of their counts goes up
+pub fn report(source: Task, status: Status) {
+  let msg = app::Message::Heartbeat(Message::ReportStatus(status));
+  util::send_message(source, &Task::Heartbeat, msg).unwrap_or_else(util::log_error);
+}
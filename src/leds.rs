@@ -0,0 +1,123 @@
+use stm32f4xx_hal::{
+  timer::{PwmHz, Channel, Ch, C1, C2, C3, C4},
+  gpio::Alternate,
+  gpio::gpiod::{PD12, PD13, PD14, PD15},
+  pac::TIM4,
+};
+
+use rtic::mutex_prelude::*;
+
+pub use stm32f4_core::leds::{
+  Led,
+  Message,
+  FULL,
+};
+use stm32f4_core::leds::{
+  Leds,
+  Action,
+};
+
+use crate::util;
+use crate::constants;
+use crate::util::debugger;
+use crate::app;
+use crate::app::{
+  leds_mb_app,
+  leds_app,
+  MessagePacket,
+};
+
+/// TIM4 with all four user LEDs on it
+pub type Pwm = PwmHz<TIM4, (Ch<C1>, Ch<C2>, Ch<C3>, Ch<C4>), (PD12<Alternate<2>>, PD13<Alternate<2>>, PD14<Alternate<2>>, PD15<Alternate<2>>)>;
+
+/// Frequency of the PWM driving the LEDs, TIM4 is on APB1
+pub const PWM_FREQ: u32 = 20_000;
+// every brightness gets a duty of its own
+const _: () = assert!(constants::TIMCLK1 / PWM_FREQ >= FULL as u32, "PWM_FREQ is too high to resolve the brightness");
+
+// time between two steps of the running effects
+const TICK_MS: u32 = 20;
+const TICK: util::Duration = util::millis(TICK_MS);
+
+/// Effects and their PWM channels. The heartbeat drives the orange LED while it
+/// is on, an effect given to it is overridden with the next step.
+pub struct Data {
+  pwm: Pwm,
+  leds: Leds,
+}
+
+
+pub fn leds_mb(cx: leds_mb_app::Context, msg: MessagePacket) {
+
+  let mut leds = cx.shared.leds;
+
+  (leds).lock(|leds| {
+
+    if let app::Message::Leds(x) = msg.msg {
+
+      let action = leds.leds.apply(&x);
+      leds.show(x.led());
+
+      if action == Action::Schedule && leds_app::spawn(app::monotonics::now()).is_err() {
+        debugger::print(format_args!("LED effects are already running"));
+      }
+    }
+  });
+}
+
+pub fn leds(cx: leds_app::Context, due: util::Instant) {
+
+  let mut leds = cx.shared.leds;
+
+  (leds).lock(|leds| {
+    // a fade that ends with this tick still has to be shown
+    let animated = Led::ALL.map(|led| leds.leds.is_animated(led));
+    let ticking = leds.leds.tick(TICK_MS);
+
+    for led in Led::ALL.iter().filter(|led| animated[led.index()]) {
+      leds.show(*led);
+    }
+
+    if ticking {
+      let next = due + TICK;
+      leds_app::spawn_at(next, next).unwrap();
+    }
+  });
+}
+
+fn channel(led: Led) -> Channel {
+  match led {
+    Led::Green => Channel::C1,
+    Led::Orange => Channel::C2,
+    Led::Red => Channel::C3,
+    Led::Blue => Channel::C4,
+  }
+}
+
+
+impl Data {
+  pub fn new(mut pwm: Pwm) -> Self {
+    for led in Led::ALL.iter() {
+      pwm.set_duty(channel(*led), 0);
+      pwm.enable(channel(*led));
+    }
+
+    Data {
+      pwm,
+      leds: Leds::new(),
+    }
+  }
+
+  /// Bypasses the effects, for drivers that work out the brightness
+  /// themselves. Scaled to the max duty the timer runs at.
+  pub fn set_brightness(&mut self, led: Led, brightness: u16) {
+    let max_duty = self.pwm.get_max_duty() as u32;
+    let duty = max_duty * brightness.min(FULL) as u32 / FULL as u32;
+    self.pwm.set_duty(channel(led), duty as u16);
+  }
+
+  // puts the brightness of the LED's effect on its channel
+  fn show(&mut self, led: Led) {
+    self.set_brightness(led, self.leds.brightness(led));
+  }
+}
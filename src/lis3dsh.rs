@@ -1,19 +1,29 @@
-use rtic_core::prelude::*;
+use rtic::mutex_prelude::*;
 
-const TIMEOUT: u32 = 3_000_000;
-const READ_MASK: u8 = 0x80;
-const WRITE_MASK: u8 = 0x00;
-
-const SCALE_BYTE_POS: u8 = 3;
-const DATA_RATE_BYTE_POS: u8 = 4;
-const BDU_BYTE_POS: u8 = 3;
-const Z_EN_BYTE_POS: u8 = 2;
-const Y_EN_BYTE_POS: u8 = 1;
-const X_EN_BYTE_POS: u8 = 0;
+pub use stm32f4_core::lis3dsh::{
+  DataRate,
+  Message,
+};
+use stm32f4_core::spi_drv::{
+  Bytes,
+  Response,
+};
+use stm32f4_core::lis3dsh::{
+  Configuration,
+  Stream,
+  State,
+  Action,
+  calculate_1g,
+};
+use stm32f4_core::time::{Micros, Millis};
 
 use crate::util;
+use crate::constants;
+use crate::util::{Requester, CorrelationId, Scheduled};
 use crate::util::debugger;
 use crate::spi_drv;
+use crate::heartbeat;
+use crate::heartbeat::Status;
 use crate::app;
 use crate::app::{
   lis_mb_app,
@@ -21,256 +31,170 @@ use crate::app::{
   Task,
 };
 
-#[derive(Debug, Clone, Copy)]
-pub enum Scale {
-  TwoG,
-  FourG,
-  SixG,
-  EightG,
-  SixteenG,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum DataRate {
-  Zero,
-  OneHundredHertz
-}
-
-#[derive(Debug)]
-pub enum Message {
-  ReadID,
-  ReadAxes,
-  ChangeScale(Scale),
-  ChangeDataRate(DataRate),
-  ChangeBDU(bool),
-  ReadComplete,
-  WriteComplete,
-  CommandRejected,
-  TimeoutCheck,
-}
-
-#[derive(Debug)]
-pub struct ReadRegister;
-
-#[allow(dead_code)]
-impl ReadRegister {
-  pub const ID: u8 = (READ_MASK | 0x0F);
-  pub const X_AXIS: u8 = (READ_MASK | 0x28);
-  pub const Y_AXIS: u8 = (READ_MASK | 0x2A);
-  pub const Z_AXIS: u8 = (READ_MASK | 0x2C);
-}
-
-#[derive(Debug)]
-pub struct WriteRegister;
-
-#[allow(dead_code)]
-impl WriteRegister {
-  pub const CTRL_REG4: u8 = (WRITE_MASK | 0x20);
-  pub const CTRL_REG5: u8 = (WRITE_MASK | 0x24);
-}
-
-struct Configuration {
-  x_en: u8,
-  y_en: u8,
-  z_en: u8,
-  // block data update
-  bdu: u8,
-  scale: Scale,
-  data_rate: DataRate
-}
+// covers the wait in the driver's queue as well as the transfer itself, which
+// the driver times out on its own well before this
+const WATCHDOG_MS: u32 = 100;
 
 pub struct Lis3dsh {
   state: State,
   config: Configuration,
-  origin: Task,
+  origin: Requester,
   current_process: Message,
+  // id of the last request handed to the SPI driver
+  transaction: CorrelationId,
+  pub stream: Stream,
+  // receives the streamed samples, they carry the id of the request that started the stream
+  subscriber: Requester,
+  // the next `StreamTick`
+  tick: Option<Scheduled>,
+  // frees the device when the completion of `transaction` never comes
+  watchdog: Option<Scheduled>,
+  // transfers whose completion never came
+  timeouts: u32,
 }
 
-#[derive(Debug)]
-pub enum Action {
-  DoNothing,
-  StartRead(spi_drv::Read),
-  StartWrite(spi_drv::Write),
-  HandleData,
-  HandleError,
-}
-
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum State {
-  Idling,
-  Busy,
-}
-
-pub fn lis3dsh_mb(cx: lis_mb_app::Context, packet: MessagePacket) {
-  (cx.resources.lis, cx.resources.spi).lock(|lis, spi| {
-
-    match packet.msg { 
-      app::Message::Lis3dsh(msg) => {
-        
-        let action;
-        (lis.state, action) = lis.state.next(&msg, lis);
-
-        match action {
-          Action::StartRead(reg) => {
-            lis.origin = packet.source;
-            lis.current_process = msg;
-            let msg = app::Message::Spi(spi_drv::Message::StartRead(reg));
-            util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-
-            let msg = app::Message::Lis3dsh(Message::TimeoutCheck);
-            util::schedule_message(Task::Lis3dsh, &Task::Lis3dsh, msg, TIMEOUT).unwrap();
-          }
-          Action::StartWrite(reg) => {
-            lis.origin = packet.source;
-            lis.current_process = msg;
-            let msg = app::Message::Spi(spi_drv::Message::StartWrite(reg));
-            util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-
-            let msg = app::Message::Lis3dsh(Message::TimeoutCheck);
-            util::schedule_message(Task::Lis3dsh, &Task::Lis3dsh, msg, TIMEOUT).unwrap();
-          }
-          Action::HandleData => {
-            match lis.current_process {
-              Message::ReadID => {
-                let res = spi.rx_buffer[0];
-                
-                // confirm the value to unblock the spi module
-                let msg = app::Message::Spi(spi_drv::Message::ReadConfirmation);
-                util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-
-                debugger::print(format_args!("Received value: {}", res));
-
-                // TODO: respond to origin
-              },
-              Message::ReadAxes => {
-                let x_axis = ((u16::from(spi.rx_buffer[1]) << 8) | u16::from(spi.rx_buffer[0])) as i16;
-                let y_axis = ((u16::from(spi.rx_buffer[3]) << 8) | u16::from(spi.rx_buffer[2])) as i16;
-                let z_axis = ((u16::from(spi.rx_buffer[5]) << 8) | u16::from(spi.rx_buffer[4])) as i16;
-
-                // confirm the value to unblock the spi module
-                let msg = app::Message::Spi(spi_drv::Message::ReadConfirmation);
-                util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-
-                let x_axis = calculate_1g(x_axis, &lis.config.scale);
-                let y_axis = calculate_1g(y_axis, &lis.config.scale);
-                let z_axis = calculate_1g(z_axis, &lis.config.scale);
-
-                debugger::print(format_args!("X-axis: {:?}", x_axis));
-                debugger::print(format_args!("Y-axis: {:?}", y_axis));
-                debugger::print(format_args!("Z-axis: {:?}", z_axis));
-
-                // TODO: respond to origin
-              }
-              Message::ChangeScale(x) => {
-                lis.config.scale = x;
-
-                // confirm the value to unblock the spi module
-                let msg = app::Message::Spi(spi_drv::Message::WriteConfirmation);
-                util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-              }
-              Message::ChangeDataRate(r) => {
-                lis.config.data_rate = r;
-
-                // confirm the value to unblock the spi module
-                let msg = app::Message::Spi(spi_drv::Message::WriteConfirmation);
-                util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-              }
-              _ => ()
-            }
-          }
-          Action::HandleError => {
-            // log error message
-            debugger::print(format_args!("[Error: {:?}] Failed to perform task: {:?}", msg, lis.current_process));
-
-            // reset the module's status to allow future communication
-            match msg {
-              Message::TimeoutCheck => {
-                // reset spi module
-                let msg = app::Message::Spi(spi_drv::Message::CancelTransaction);
-                util::send_message(Task::Lis3dsh, &Task::Spi1, msg).unwrap();
-              }
-              _ => ()
-            }
-          }
-          Action::DoNothing => (),
+pub fn lis3dsh_mb(mut cx: lis_mb_app::Context, packet: MessagePacket) {
+  let requester = packet.requester();
+
+  (cx.shared.lis).lock(|lis| {
+    let (msg, data) = match packet.msg {
+      // completions from anywhere but the driver would come without their data
+      app::Message::Lis3dsh(Message::Watchdog(id)) if id != lis.transaction => return,
+      app::Message::Lis3dsh(msg) if !msg.is_completion() => (msg, Bytes::new()),
+      // results of transactions that have been given up on are dropped
+      app::Message::SpiResult(completion) if packet.id == lis.transaction => {
+        if let Some(watchdog) = lis.watchdog.take() {
+          watchdog.cancel();
+        }
+        let msg = Message::from(&completion);
+        match completion.result {
+          Ok(Response::Read(data)) => (msg, data),
+          _ => (msg, Bytes::new()),
         }
       }
-      _ => ()
-    }
-  });
-}
-
-fn calculate_1g(input: i16, scale: &Scale) -> f32 {
-  let scale: u8 = match scale {
-    Scale::TwoG => 2,
-    Scale::FourG => 4,
-    Scale::SixG => 6,
-    Scale::EightG => 8,
-    Scale::SixteenG => 16,
-  };
-
-  // division is by 2^15 because value is signed 16-bit
-  (f32::from(input) * f32::from(scale)) / 32768.0
-}
-
-
-impl State {
-  pub fn next(self, msg: &Message, lis: &Lis3dsh) -> (State, Action) {
-    match (self, msg) {
-      (State::Idling, Message::ReadID) => {
-        (State::Busy, Action::StartRead(spi_drv::Read { reg: ReadRegister::ID, len: 1 }))
-      }
-      (State::Idling, Message::ReadAxes) => {
-        // auto-increment is enabled by default so this will read out all the axis registers
-        (State::Busy, Action::StartRead(spi_drv::Read { reg: ReadRegister::X_AXIS, len: 6 }))
+      _ => return,
+    };
+
+    let action;
+    (lis.state, action) = lis.state.next(&msg, &lis.config, &lis.stream);
+
+    match action {
+      Action::StartRead(reg) => {
+        lis.origin = requester;
+        lis.current_process = msg;
+        if !lis.request(|id| spi_drv::Message::StartRead(id, reg)) {
+          lis.reject();
+        }
       }
-      (State::Idling, Message::ChangeScale(scale)) => {
-        let scale = u8::from(*scale);
-        let mut data = [0; 10];
-        data[0] = scale << SCALE_BYTE_POS;
-
-        (State::Busy, Action::StartWrite(spi_drv::Write { reg: WriteRegister::CTRL_REG5, len: 2, data }))
+      Action::StartWrite(reg) => {
+        lis.origin = requester;
+        lis.current_process = msg;
+        if !lis.request(|id| spi_drv::Message::StartWrite(id, reg)) {
+          lis.reject();
+        }
       }
-      (State::Idling, Message::ChangeDataRate(rate)) => {
-        let rate = u8::from(*rate);
-        let mut data = [0; 10];
-        data[0] = u8::from(rate) |
-          lis.config.bdu |
-          lis.config.z_en |
-          lis.config.y_en |
-          lis.config.x_en;
+      Action::HandleData => {
+        match lis.current_process {
+          Message::ReadID => {
+            let res = match data.first() {
+              Some(res) => *res,
+              None => {
+                debugger::print(format_args!("LIS3DSH ID read came back empty"));
+                return;
+              }
+            };
+
+            debugger::print(format_args!("Received value: {}", res));
+
+            let msg = app::Message::DeviceId(res);
+            util::reply(Task::Lis3dsh, &lis.origin, msg).unwrap_or_else(util::log_error);
+          },
+          Message::ReadAxes | Message::StreamTick(_) => {
+            let axes = match data.get(..6) {
+              Some(&[x_l, x_h, y_l, y_h, z_l, z_h]) => [[x_l, x_h], [y_l, y_h], [z_l, z_h]],
+              _ => {
+                debugger::print(format_args!("LIS3DSH axis read came back with {} bytes", data.len()));
+                return;
+              }
+            };
+            let [x_axis, y_axis, z_axis] = axes.map(i16::from_le_bytes);
 
-        (State::Busy, Action::StartWrite(spi_drv::Write { reg: WriteRegister::CTRL_REG4, len: 2, data }))
-      }
-      (State::Idling, Message::ChangeBDU(bdu)) => {
-        let bdu = u8::from(*bdu);
-        let mut data = [0; 10];
+            let x_axis = calculate_1g(x_axis, &lis.config.ctrl_reg5.scale);
+            let y_axis = calculate_1g(y_axis, &lis.config.ctrl_reg5.scale);
+            let z_axis = calculate_1g(z_axis, &lis.config.ctrl_reg5.scale);
 
-        data[0] = (bdu << BDU_BYTE_POS) |
-          u8::from(lis.config.data_rate) |
-          lis.config.z_en |
-          lis.config.y_en |
-          lis.config.x_en;
+            debugger::print(format_args!("X-axis: {:?}", x_axis));
+            debugger::print(format_args!("Y-axis: {:?}", y_axis));
+            debugger::print(format_args!("Z-axis: {:?}", z_axis));
 
-        (State::Busy, Action::StartWrite(spi_drv::Write { reg: WriteRegister::CTRL_REG4, len: 2, data }))
+            let msg = app::Message::AccelSample { x: x_axis, y: y_axis, z: z_axis };
+            util::reply(Task::Lis3dsh, &lis.origin, msg).unwrap_or_else(util::log_error);
+          }
+          Message::ChangeScale(_) |
+          Message::ChangeBandwidth(_) |
+          Message::ChangeSelfTest(_) |
+          Message::ChangeDataRate(_) |
+          Message::ChangeBDU(_) => {
+            lis.config = lis.config.with(&lis.current_process);
+            lis.stream.retime(lis.config.ctrl_reg4.data_rate);
+          }
+          _ => ()
+        }
       }
-      (State::Busy, Message::ReadComplete) => {
-        (State::Idling, Action::HandleData)
+      Action::HandleError => {
+        // the spi driver has already released the bus and counted its own
+        // timeouts, only completions it never got to send are counted here
+        if let Message::Watchdog(_) = msg {
+          lis.timeouts = lis.timeouts.wrapping_add(1);
+          heartbeat::report(Task::Lis3dsh, Status::LisTimeouts(lis.timeouts));
+        }
+        debugger::print(format_args!("[Error: {:?}] Failed to perform task: {:?}", msg, lis.current_process));
       }
-      (State::Busy, Message::WriteComplete) => {
-        (State::Idling, Action::HandleData)
+      Action::StartStream(period_us) => {
+        lis.subscriber = requester;
+        lis.stream.start(period_us);
+        lis.schedule_tick();
       }
-      (State::Busy, Message::TimeoutCheck) => {
-        (State::Idling, Action::HandleError)
+      Action::StopStream => {
+        lis.stream.stop();
+        if let Some(tick) = lis.tick.take() {
+          tick.cancel();
+        }
       }
-      (s, Message::CommandRejected) => {
-        (s, Action::HandleError)
+      Action::StreamRead(reg) => {
+        rearm_stream(lis);
+
+        // samples go to whoever started the stream
+        lis.origin = lis.subscriber;
+        lis.current_process = msg;
+        if !lis.request(|id| spi_drv::Message::StartRead(id, reg)) {
+          lis.reject();
+        }
       }
-      (s, _m) => {
-        (s, Action::DoNothing)
+      Action::DropSample => {
+        lis.stream.dropped += 1;
+        rearm_stream(lis);
       }
+      Action::DoNothing => (),
     }
-  }
+  });
+}
+
+fn rearm_stream(lis: &mut Lis3dsh) {
+  let period = Micros(lis.stream.period_us);
+  let msg = app::Message::Lis3dsh(Message::StreamTick(lis.stream.generation));
+
+  // the tick being handled is still the last one scheduled. the next one is
+  // timed from when it was due, unless a whole period has been missed
+  let next = match lis.tick.take() {
+    Some(tick) if Micros::from_cycles(util::cycles_since(tick.due()), constants::CPU_FREQ) > period => {
+      lis.stream.late += 1;
+      tick.reschedule(Task::Lis3dsh, msg, period)
+    }
+    Some(tick) => tick.repeat(Task::Lis3dsh, msg, period),
+    None => util::schedule_message(Task::Lis3dsh, &Task::Lis3dsh, msg, period),
+  };
+  lis.tick = next.map_err(util::log_error).ok();
 }
 
 impl Lis3dsh {
@@ -278,42 +202,55 @@ impl Lis3dsh {
     Lis3dsh {
       state: State::Idling,
       config: Configuration::default(),
-      origin: Task::Init,
-      current_process: Message::CommandRejected
+      origin: Requester { task: Task::Init, id: 0 },
+      current_process: Message::CommandRejected,
+      transaction: 0,
+      stream: Stream::default(),
+      subscriber: Requester { task: Task::Init, id: 0 },
+      tick: None,
+      watchdog: None,
+      timeouts: 0,
     }
   }
-}
 
-impl From<Scale> for u8 {
-  fn from(x: Scale) -> Self {
-    match x {
-      Scale::TwoG => (0 << SCALE_BYTE_POS),
-      Scale::FourG => (1 << SCALE_BYTE_POS),
-      Scale::SixG => (2 << SCALE_BYTE_POS),
-      Scale::EightG => (3 << SCALE_BYTE_POS),
-      Scale::SixteenG => (4 << SCALE_BYTE_POS),
-    }
+  /// A restarted stream replaces the tick of the old one
+  fn schedule_tick(&mut self) {
+    let msg = app::Message::Lis3dsh(Message::StreamTick(self.stream.generation));
+    let period = Micros(self.stream.period_us);
+
+    let next = match self.tick.take() {
+      Some(tick) => tick.reschedule(Task::Lis3dsh, msg, period),
+      None => util::schedule_message(Task::Lis3dsh, &Task::Lis3dsh, msg, period),
+    };
+    self.tick = next.map_err(util::log_error).ok();
   }
-}
 
-impl From<DataRate> for u8 {
-  fn from(x: DataRate) -> Self {
-    match x {
-      DataRate::Zero => (0 << DATA_RATE_BYTE_POS),
-      DataRate::OneHundredHertz => (6 << DATA_RATE_BYTE_POS)
+  /// Sends a request to the SPI driver, the completion comes back with the
+  /// same id, which doubles as the driver's transaction id. Returns false if
+  /// the driver never got it
+  fn request(&mut self, msg: impl FnOnce(CorrelationId) -> spi_drv::Message) -> bool {
+    self.transaction = util::next_id();
+    let packet = MessagePacket::with_id(Task::Lis3dsh, self.transaction, app::Message::Spi(msg(self.transaction)));
+    if let Err(e) = util::send_packet(&Task::Spi1, packet) {
+      util::log_error(e);
+      return false;
     }
+
+    let msg = app::Message::Lis3dsh(Message::Watchdog(self.transaction));
+    let watchdog = match self.watchdog.take() {
+      Some(watchdog) => watchdog.reschedule(Task::Lis3dsh, msg, Millis(WATCHDOG_MS)),
+      None => util::schedule_message(Task::Lis3dsh, &Task::Lis3dsh, msg, Millis(WATCHDOG_MS)),
+    };
+    self.watchdog = watchdog.map_err(util::log_error).ok();
+    true
   }
-}
 
-impl Default for Configuration {
-  fn default() -> Self {
-    Configuration {
-      x_en: (1 << X_EN_BYTE_POS),
-      y_en: (1 << Y_EN_BYTE_POS),
-      z_en: (1 << Z_EN_BYTE_POS),
-      bdu: (0 << BDU_BYTE_POS),
-      scale: Scale::TwoG,
-      data_rate: DataRate::Zero
+  /// A request the driver never got is handled like one it rejected
+  fn reject(&mut self) {
+    let action;
+    (self.state, action) = self.state.next(&Message::CommandRejected, &self.config, &self.stream);
+    if let Action::HandleError = action {
+      debugger::print(format_args!("[Error: {:?}] Failed to perform task: {:?}", Message::CommandRejected, self.current_process));
     }
   }
-}
+}
\ No newline at end of file
@@ -1,11 +1,10 @@
 // #![deny(unsafe_code)]
 #![no_main]
 #![no_std]
-#![feature(destructuring_assignment)]
 
 #[allow(unused_extern_crates)]
-
 mod heartbeat;
+mod leds;
 mod util;
 mod constants;
 mod button;
@@ -14,9 +13,8 @@ mod lis3dsh;
 // mod i2c_drv;
 
 #[rtic::app(
-  device = stm32f4xx_hal::stm32,
+  device = stm32f4xx_hal::pac,
   peripherals = true,
-  monotonic = rtic::cyccnt::CYCCNT,
   dispatchers = [SDIO, CRYP]
 )]
 mod app {
@@ -24,8 +22,7 @@ mod app {
   use cortex_m::asm;
   use stm32f4xx_hal::{
     prelude::*,
-    stm32,
-    pwm,
+    pac,
     spi,
     gpio::gpioa,
     gpio::gpioe,
@@ -33,44 +30,68 @@ mod app {
     gpio::gpioa::PA5,
     gpio::gpioa::PA6,
     gpio::gpioa::PA7,
-    gpio::AF5,
     gpio::Output,
     gpio::Input,
     gpio::Alternate,
-    gpio::Floating,
     gpio::Edge,
     gpio::PushPull,
     gpio::ExtiPin,
-    pwm::C2,
-    stm32::TIM4,
-    stm32::SPI1,
+    pac::SPI1,
   };
-  use rtic_core::prelude::*;
+  use dwt_systick_monotonic::DwtSystick;
+  use crate::constants::CPU_FREQ;
+  use stm32f4_core::time::Seconds;
 
   use panic_semihosting as _;
 
-  #[resources]
-  struct Resources {
-    heartbeat: heartbeat::Data<TIM4, C2>,
-    button: button::Data<gpioa::PA0<Input<Floating>>>,
-    spi: spi_drv::spi1::Data<spi::Spi<SPI1, (PA5<Alternate<AF5>>, PA6<Alternate<AF5>>, PA7<Alternate<AF5>>)>, gpioe::PE3<Output<PushPull>>>,
+  // CYCCNT keeps the time, SysTick wakes the scheduler up
+  #[monotonic(binds = SysTick, default = true)]
+  type Mono = DwtSystick<CPU_FREQ>;
+
+  type Spi1 = spi::Spi<SPI1, (PA5<Alternate<5>>, PA6<Alternate<5>>, PA7<Alternate<5>>)>;
+
+  #[shared]
+  struct Shared {
+    heartbeat: heartbeat::Data,
+    leds: leds::Data,
+    button: button::Data<gpioa::PA0<Input>>,
+    spi: spi_drv::spi1::Data<Spi1, gpioe::PE3<Output<PushPull>>>,
     lis: lis3dsh::Lis3dsh,
-    exti: stm32::EXTI,
+    exti: pac::EXTI,
   }
 
-  #[init()]
-  fn init(cx: init::Context) -> init::LateResources {
+  #[local]
+  struct Local {}
+
+  #[init]
+  fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
 
     util::debugger::init();
     util::debugger::print(format_args!("Initializing"));
 
     // device specific peripherals
-    let device: stm32::Peripherals = cx.device;
-    let mut syscfg = device.SYSCFG;
+    let device: pac::Peripherals = cx.device;
+    let mut core = cx.core;
+    let mut syscfg = device.SYSCFG.constrain();
     let mut exti = device.EXTI;
 
+    // the SPI driver programs the DMA streams itself
+    device.RCC.ahb1enr.modify(|_, w| w.dma2en().set_bit());
     let rcc = device.RCC.constrain();
-    let clocks = rcc.cfgr.sysclk(168.mhz()).freeze();
+    let clocks = rcc.cfgr
+      .use_hse(constants::HSE.Hz())
+      .sysclk(constants::SYSCLK.Hz())
+      .hclk(constants::HCLK.Hz())
+      .pclk1(constants::PCLK1.Hz())
+      .pclk2(constants::PCLK2.Hz())
+      .freeze();
+    // the HAL rounds to what it can reach, everything else is timed from the
+    // constants. the monotonic counts HCLK cycles as `CPU_FREQ`
+    assert!(clocks.sysclk().raw() == constants::SYSCLK, "SYSCLK differs from constants.rs");
+    assert!(clocks.hclk().raw() == constants::HCLK, "HCLK differs from constants.rs");
+    assert!(clocks.pclk1().raw() == constants::PCLK1, "PCLK1 differs from constants.rs");
+    assert!(clocks.pclk2().raw() == constants::PCLK2, "PCLK2 differs from constants.rs");
+    let mono = DwtSystick::new(&mut core.DCB, core.DWT, core.SYST, clocks.hclk().raw());
 
     let gpioa = device.GPIOA.split();
     let gpiod = device.GPIOD.split();
@@ -79,65 +100,76 @@ mod app {
     // configure button as interrupt source
     let mut button = gpioa.pa0.into_floating_input();
     button.make_interrupt_source(&mut syscfg);
-    button.trigger_on_edge(&mut exti, Edge::RISING);
+    button.trigger_on_edge(&mut exti, Edge::Rising);
     button.enable_interrupt(&mut exti);
 
-    // configure PWM module
-    let pwm_channel = gpiod.pd13.into_alternate_af2();
-    let mut heartbeat_led= pwm::tim4(device.TIM4, pwm_channel, clocks, 20u32.khz());
-    heartbeat_led.set_duty(0);
+    // configure PWM module, one channel per user LED
+    let led_pins = (gpiod.pd12.into_alternate(), gpiod.pd13.into_alternate(), gpiod.pd14.into_alternate(), gpiod.pd15.into_alternate());
+    let pwm = device.TIM4.pwm_hz(led_pins, leds::PWM_FREQ.Hz(), &clocks);
 
     // setup SPI1 for accelerometer
-    let spi_clk = gpioa.pa5.into_alternate_af5();
-    let spi_miso = gpioa.pa6.into_alternate_af5();
-    let spi_mosi = gpioa.pa7.into_alternate_af5();
+    let spi_clk = gpioa.pa5.into_alternate();
+    let spi_miso = gpioa.pa6.into_alternate();
+    let spi_mosi = gpioa.pa7.into_alternate();
     let mut spi_cs = gpioe.pe3.into_push_pull_output();
-    let mode = spi::Mode {
-      polarity: spi::Polarity::IdleLow,
-      phase: spi::Phase::CaptureOnFirstTransition
-    };
+    // the bus starts out in the accelerometer's mode, the driver switches
+    // modes when a transaction for another device comes up
+    let mode = spi_drv::Device::Lis3dsh.mode();
 
-    let mut spi1 = spi::Spi::spi1(device.SPI1,
+    let mut spi1 = spi::Spi::new(device.SPI1,
       (spi_clk, spi_miso, spi_mosi),
       mode,
-      1u32.mhz().into(),
-      clocks);
+      1.MHz(),
+      &clocks);
     
     spi1.listen(spi::Event::Error);
-    spi_cs.set_high().unwrap();
+    spi_cs.set_high();
 
     // initialize resource data
     let button = button::Data::new(button);
-    let heartbeat = heartbeat::Data::new(heartbeat_led);
+    let heartbeat = heartbeat::Data::new();
+    let leds = leds::Data::new(pwm);
     let lis = lis3dsh::Lis3dsh::new();
-    let spi = spi_drv::spi1::Data::new(spi1, spi_cs);
+    // the transfer modes take turns so the usage report compares them
+    let spi = spi_drv::spi1::Data::new(spi1, [spi_cs], mode, spi_drv::TransferMode::Interrupt);
 
     // start tasks
     let msg = Message::Heartbeat(heartbeat::Message::TurnOn);
     util::send_message(Task::Init, &Task::Heartbeat, msg).unwrap();
 
+    // a short press toggles the heartbeat. it is only told apart from a double
+    // click once the click window is over, `Timing::double_click_ms` after
+    // the release
+    let packet = MessagePacket::new(Task::Init, Message::Button(button::Message::Subscribe)).reply_to(Task::Heartbeat);
+    util::send_packet(&Task::Button, packet).unwrap();
+
+    // a double click prints the CPU time SPI1 took per transaction so far
+    let packet = MessagePacket::new(Task::Init, Message::Button(button::Message::Subscribe)).reply_to(Task::Spi1);
+    util::send_packet(&Task::Button, packet).unwrap();
+
     let msg = Message::Lis3dsh(lis3dsh::Message::ChangeDataRate(lis3dsh::DataRate::OneHundredHertz));
     util::send_message(Task::Init, &Task::Lis3dsh, msg).unwrap();
 
-    let msg = Message::Lis3dsh(lis3dsh::Message::ReadAxes);
-    util::schedule_message(Task::Init, &Task::Lis3dsh, msg, 1_000_000).unwrap();
+    let msg = Message::Lis3dsh(lis3dsh::Message::StartStreaming { period_us: 1_000_000 });
+    util::schedule_message(Task::Init, &Task::Lis3dsh, msg, Seconds(1)).unwrap();
 
-    init::LateResources {
+    (Shared {
       heartbeat,
+      leds,
       button,
       lis,
       exti,
       spi,
-    }
+    }, Local {}, init::Monotonics(mono))
   }
 
-  #[task(priority = 3, binds = EXTI0, resources = [button, exti])]
+  #[task(priority = 3, binds = EXTI0, shared = [button, exti])]
   fn exti0(cx: exti0::Context) {
 
-    util::send_message(Task::Interrupt, &Task::Button, Message::Button(button::Message::ButtonPressed)).unwrap();
+    util::send_message(Task::Interrupt, &Task::Button, Message::Button(button::Message::ButtonPressed)).unwrap_or_else(util::log_error);
 
-    let exti = cx.resources.exti;
-    let button = cx.resources.button;
+    let exti = cx.shared.exti;
+    let button = cx.shared.button;
     
     (button, exti).lock(|button, exti| {
         button.button.clear_interrupt_pending_bit();
@@ -145,12 +177,17 @@ mod app {
     });
   }
 
-  #[task(priority = 3, binds = SPI1, resources = [spi])]
+  #[task(priority = 3, binds = SPI1, shared = [spi])]
   fn spi1(mut cx: spi1::Context) {
+    let start = monotonics::now();
     let mut msg = Message::Spi(spi_drv::Message::Ignore);
 
-    (cx.resources.spi).lock(|spi| {
-      if spi.spi.is_rxne() {
+    (cx.shared.spi).lock(|spi| {
+      // errors go first, the data register is not worth reading once one is flagged
+      if let Some(e) = spi.take_error() {
+        msg = Message::Spi(spi_drv::Message::Error(e));
+        spi.spi.unlisten(spi::Event::Rxne);
+      } else if spi.spi.is_rxne() {
         // util::debugger::print(format_args!("RX not empty event"));
         msg = Message::Spi(spi_drv::Message::RxEvent);
         spi.spi.unlisten(spi::Event::Rxne);
@@ -160,42 +197,113 @@ mod app {
       } else {
         // util::debugger::print(format_args!("Unknown event received"));
       }
+
+      spi.charge(start);
     });
 
-    util::send_message(Task::Interrupt, &Task::Spi1, msg).unwrap();
+    util::send_message(Task::Interrupt, &Task::Spi1, msg).unwrap_or_else(util::log_error);
   }
 
-  #[task(priority = 2, resources = [lis, spi], capacity = 4)]
+  // SPI1_RX stream, completes after the last byte of a DMA transfer came in
+  #[task(priority = 3, binds = DMA2_STREAM0, shared = [spi])]
+  fn dma2_stream0(mut cx: dma2_stream0::Context) {
+    let start = monotonics::now();
+
+    let msg = (cx.shared.spi).lock(|spi| {
+      let msg = match spi.take_dma_error() {
+        Some(e) => spi_drv::Message::Error(e),
+        None => spi_drv::Message::DmaComplete,
+      };
+      spi.stop_dma();
+      spi.charge(start);
+      msg
+    });
+
+    util::send_message(Task::Interrupt, &Task::Spi1, Message::Spi(msg)).unwrap_or_else(util::log_error);
+  }
+
+  // SPI1_TX stream, only enabled for its errors
+  #[task(priority = 3, binds = DMA2_STREAM3, shared = [spi])]
+  fn dma2_stream3(mut cx: dma2_stream3::Context) {
+    let start = monotonics::now();
+
+    let error = (cx.shared.spi).lock(|spi| {
+      // the RX stream may have reported it already
+      let error = spi.take_dma_error();
+      if error.is_some() {
+        spi.stop_dma();
+      }
+      spi.charge(start);
+      error
+    });
+
+    if let Some(e) = error {
+      util::send_message(Task::Interrupt, &Task::Spi1, Message::Spi(spi_drv::Message::Error(e))).unwrap_or_else(util::log_error);
+    }
+  }
+
+  // mailbox tasks, what happens once they are full is registered in util.rs
+  #[task(priority = 2, shared = [lis], capacity = 4)]
   fn lis_mb_app(cx: lis_mb_app::Context, msg: MessagePacket) {
-    lis3dsh::lis3dsh_mb(cx, msg);
+    if let Some(msg) = util::receive(Task::Lis3dsh, msg) {
+      lis3dsh::lis3dsh_mb(cx, msg);
+    }
   }
 
-  #[task(priority = 2, resources = [spi], capacity = 2)]
+  // room for the queued requests plus their pending timeouts
+  #[task(priority = 2, shared = [spi], capacity = 8)]
   fn spi1_mb_app(cx: spi1_mb_app::Context, msg: MessagePacket) {
-    spi_drv::spi1::spi1_mb(cx, msg);
+    if let Some(msg) = util::receive(Task::Spi1, msg) {
+      spi_drv::spi1::spi1_mb(cx, msg);
+    }
   }
 
-  #[task(priority = 2, resources = [button])]
+  #[task(priority = 2, shared = [button], capacity = 4)]
   fn button_mb_app(cx: button_mb_app::Context, msg: MessagePacket) {
-    button::button_mb(cx, msg);
+    if let Some(msg) = util::receive(Task::Button, msg) {
+      button::button_mb(cx, msg);
+    }
+  }
+
+  // messages that found their mailbox full, see `util::Overflow`
+  #[task(capacity = 4)]
+  fn retry_app(_: retry_app::Context, dest: Task, msg: MessagePacket, attempt: u8) {
+    util::retry(dest, msg, attempt);
   }
 
-  #[task(resources = [button, exti])]
+  #[task(shared = [button, exti])]
   fn button_app(cx: button_app::Context) {
     button::button(cx);
   }
 
-  #[task(priority = 2, resources = [heartbeat])]
+  #[task(priority = 2, shared = [heartbeat], capacity = 4)]
   fn heartbeat_mb_app(cx: heartbeat_mb_app::Context, msg: MessagePacket) {
-    heartbeat::heartbeat_mb(cx, msg);
+    if let Some(msg) = util::receive(Task::Heartbeat, msg) {
+      heartbeat::heartbeat_mb(cx, msg);
+    }
+  }
+
+  // `due` is when this step was meant to run, the next one is timed from it
+  #[task(shared = [heartbeat, leds])]
+  fn heartbeat_app(cx: heartbeat_app::Context, due: util::Instant, elapsed_ms: u32) {
+    heartbeat::heartbeat(cx, due, elapsed_ms);
+  }
+
+  // room for a command to each LED
+  #[task(priority = 2, shared = [leds], capacity = 4)]
+  fn leds_mb_app(cx: leds_mb_app::Context, msg: MessagePacket) {
+    if let Some(msg) = util::receive(Task::Leds, msg) {
+      leds::leds_mb(cx, msg);
+    }
   }
 
-  #[task(resources = [heartbeat])]
-  fn heartbeat_app(cx: heartbeat_app::Context, increment: bool) {
-    heartbeat::heartbeat(cx, increment);
+  // steps the running LED effects, timed from `due` like the heartbeat
+  #[task(shared = [leds])]
+  fn leds_app(cx: leds_app::Context, due: util::Instant) {
+    leds::leds(cx, due);
   }
 
-  #[idle()]
+  #[idle]
   fn idle(_cx: idle::Context) -> ! {
     loop {
       // sleep while waiting for next event
@@ -216,27 +324,34 @@ mod app {
   //   cx.schedule.blink(cx.scheduled + CPU_FREQ.cycles()).unwrap();
   // }
 
-  #[derive(Debug)]
-  pub enum Task {
-    Init,
-    Interrupt,
-    Heartbeat,
-    Button,
-    Spi1,
-    Lis3dsh
-  }
+  // generated together with the router, see util.rs
+  pub use crate::util::Task;
 
   #[derive(Debug)]
   pub enum Message {
     Lis3dsh(lis3dsh::Message),
     Heartbeat(heartbeat::Message),
+    Leds(leds::Message),
     Button(button::Message),
     Spi(spi_drv::Message),
+    // outcome of a transaction on the SPI bus
+    SpiResult(spi_drv::Completion),
+    SpiErrors(spi_drv::ErrorCounters),
+    // responses from the accelerometer, axis values are in g
+    AccelSample { x: f32, y: f32, z: f32 },
+    DeviceId(u8),
   }
 
   #[derive(Debug)]
   pub struct MessagePacket {
     pub source: Task,
+    // a reply carries the id of its request, see `util::reply`
+    pub id: util::CorrelationId,
+    // where replies go instead of the source
+    pub reply_to: Option<Task>,
+    // the id a scheduled message is tracked under until it arrives, replies
+    // reuse ids so immediate messages are never matched against the timers
+    pub timer: Option<util::CorrelationId>,
     pub msg: Message
   }
 }
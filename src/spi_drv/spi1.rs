@@ -1,16 +1,43 @@
-use rtic_core::prelude::*;
+use rtic::mutex_prelude::*;
+use core::convert::Infallible;
+
+use stm32f4xx_hal::hal::digital::v2::OutputPin;
+use stm32f4xx_hal::hal::spi::{Mode, Phase, Polarity};
 use stm32f4xx_hal::{
-  prelude::*,
+  nb,
   spi,
+  pac::{SPI1, DMA2},
+};
+use stm32f4_core::spi_drv::{
+  Device,
+  Request,
+  Response,
+  Completion,
+  Error,
+  Bytes,
+  TransactionId,
+  TransferMode,
+  DEVICE_COUNT,
+  spi1::State,
+  queue::Queue,
+  deadline::{Deadline, timeout_us},
+  transfer::{Transfer, Progress},
+  dma::Frame,
+  benchmark::Benchmark,
+  errors::ErrorCounters,
 };
+use stm32f4_core::time::Micros;
 
-use crate::lis3dsh;
 use crate::util;
+use crate::util::{Requester, Scheduled};
+use crate::util::debugger;
+use crate::heartbeat;
+use crate::heartbeat::Status;
+use crate::button;
+use crate::button::Gesture;
 use crate::spi_drv::{
   Message,
   Action,
-  TX_BUFFER_SIZE,
-  RX_BUFFER_SIZE,
 };
 use crate::app;
 use crate::app::{
@@ -19,179 +46,410 @@ use crate::app::{
   Task,
 };
 
+// SPI1_RX is on DMA2 stream 0 and SPI1_TX on stream 3, channel 3 for both
+const RX_STREAM: usize = 0;
+const TX_STREAM: usize = 3;
+const DMA_CHANNEL: u8 = 3;
+
 pub struct Data<T, U> {
   pub spi: T,
-  cs_pin: U,
+  // chip-select lines indexed by `Device::index`
+  cs_pins: [U; DEVICE_COUNT],
+  mode: Mode,
   state: State,
-  origin: Task,
-  tx_buffer: [u8; TX_BUFFER_SIZE],
-  pub rx_buffer: [u8; RX_BUFFER_SIZE],
-  transfer_len: u8,
-  bytes_transferred: u8
-}
-
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum State {
-  Idling,
-  Reading,
-  Writing,
-  WaitingForConfirmation,
+  // the transaction on the bus and the task that asked for it
+  id: TransactionId,
+  device: Device,
+  origin: Requester,
+  deadline: Deadline,
+  // the scheduled `Timeout` of the transaction on the bus
+  timeout: Option<Scheduled>,
+  queue: Queue<Requester>,
+  transfer_mode: TransferMode,
+  transfer: Transfer,
+  frame: Frame,
+  // cycles spent in the driver and its interrupts, per transfer mode
+  pub benchmark: Benchmark,
+  pub errors: ErrorCounters,
 }
 
 pub fn spi1_mb(mut cx: spi1_mb_app::Context, msg: MessagePacket) {
-  (cx.resources.spi).lock(|spi| {
-
-    match msg.msg { 
-      app::Message::Spi(x) => {
-
-        let action;
-        (spi.state, action) = spi.state.next(&x);
+  let start = app::monotonics::now();
+  let requester = msg.requester();
+  let source = msg.source;
 
-        match action {
-          Action::StartRead(r) => {
-            spi.origin = msg.source;
+  let msg = match msg.msg {
+    app::Message::Spi(x) => Some(x),
+    // subscribed to the button in `init`
+    app::Message::Button(button::Message::Gesture(Gesture::DoubleClick)) => Some(Message::ReportUsage),
+    _ => None,
+  };
 
-            spi.spi.listen(spi::Event::Rxne);
-            spi.cs_pin.set_low().unwrap();
-            spi.tx_buffer[0] = r.reg;
-            spi.transfer_len = r.len;
-            spi.spi.send(spi.tx_buffer[0]).unwrap();
-          }
-          Action::StartWrite(w) => {
-            spi.origin = msg.source;
-
-            spi.spi.listen(spi::Event::Rxne);
-            spi.cs_pin.set_low().unwrap();
+  (cx.shared.spi).lock(|spi| {
 
-            spi.tx_buffer[0] = w.reg;
-            spi.transfer_len = w.len;
+    if let Some(x) = msg {
 
-            let mut i: usize = 0;
-            while i < w.len.into() {
-              spi.tx_buffer[i + 1] = w.data[i];
-              i += 1;
-            }
+      let action;
+      (spi.state, action) = spi.state.next(&x, &spi.deadline);
 
-            spi.spi.send(spi.tx_buffer[0]).unwrap();
+      match action {
+        Action::StartRead(id, r) => {
+          spi.start(requester, Request::Read(id, r));
+        }
+        Action::StartWrite(id, w) => {
+          spi.start(requester, Request::Write(id, w));
+        }
+        Action::Enqueue(request) => {
+          if let Err(pending) = spi.queue.push(requester, request) {
+            debugger::print(format_args!("SPI queue full, rejecting {:?}", pending.request));
+            reply(pending.origin, Completion::rejected(&pending.request));
           }
-          Action::ContinueRead => {
-            let val = spi.spi.read().unwrap();
-
-            // the first byte is garbage data
-            if spi.bytes_transferred > 0 {
-              spi.rx_buffer[(usize::from(spi.bytes_transferred) - 1)] = val;
-            }
-
-            if spi.bytes_transferred == spi.transfer_len {
-              (spi.state, ..) = spi.state.next(&Message::FinishTransaction);
-
-              spi.cs_pin.set_high().unwrap();
-
-              util::send_message(Task::Spi1, &spi.origin, app::Message::Lis3dsh(lis3dsh::Message::ReadComplete)).unwrap();
-
-              // reset transaction
-              spi.transfer_len = 0;
-              spi.bytes_transferred = 0;
-            } else {
-              // send 0x00 as dummy byte to keep transfer going
-              spi.spi.listen(spi::Event::Rxne);
-              spi.spi.send(0x00).unwrap();
-              spi.bytes_transferred += 1;
-            }
-          },
-          Action::ContinueWrite => {
-            // read out the value from spi module and ignore it
-            spi.spi.read().unwrap();
-            spi.bytes_transferred += 1;
-
-            if spi.bytes_transferred == spi.transfer_len {
-
-              (spi.state, ..) = spi.state.next(&Message::FinishTransaction);
-              spi.cs_pin.set_high().unwrap();
-
-              // spawn message to origin
-              util::send_message(Task::Spi1, &spi.origin, app::Message::Lis3dsh(lis3dsh::Message::WriteComplete)).unwrap();
-              
-              // reset transaction
-              spi.transfer_len = 0;
-              spi.bytes_transferred = 0;
-              
-            } else {
-              spi.spi.listen(spi::Event::Rxne);
-              spi.spi.send(spi.tx_buffer[usize::from(spi.bytes_transferred)]).unwrap();
+        }
+        Action::StartNext => {
+          spi.start_next();
+        }
+        Action::ContinueRead => {
+          let cs_pin = &mut spi.cs_pins[spi.device.index()];
+          match spi.transfer.continue_read(&mut spi.spi, cs_pin) {
+            Ok(Progress::InProgress) => spi.spi.listen(spi::Event::Rxne),
+            Ok(Progress::Done) => {
+              // received() never exceeds the capacity of Bytes
+              let data = Bytes::from_slice(spi.transfer.received()).unwrap();
+              spi.finish(Ok(Response::Read(data)));
             }
+            Err(e) => spi.fail(bus_error(e)),
           }
-          Action::Reject => {
-            match spi.origin {
-              Task::Lis3dsh => {
-                util::send_message(Task::Spi1, &spi.origin, app::Message::Lis3dsh(lis3dsh::Message::CommandRejected)).unwrap();
-              }
-              _ => ()
+        },
+        Action::ContinueWrite => {
+          let cs_pin = &mut spi.cs_pins[spi.device.index()];
+          match spi.transfer.continue_write(&mut spi.spi, cs_pin) {
+            Ok(Progress::InProgress) => spi.spi.listen(spi::Event::Rxne),
+            Ok(Progress::Done) => {
+              spi.finish(Ok(Response::Written));
             }
+            Err(e) => spi.fail(bus_error(e)),
           }
-          Action::Reset => {
-            // reset variables
-            spi.transfer_len = 0;
-            spi.bytes_transferred = 0;
-          }
-          Action::DoNothing => (),
         }
+        Action::Abort(e) => {
+          spi.abort(e);
+        }
+        Action::CountError(e) => {
+          debugger::print(format_args!("SPI1 error while idling: {:?}", e));
+          spi.count_error(e);
+        }
+        Action::ReportErrors => {
+          util::reply(Task::Spi1, &requester, app::Message::SpiErrors(spi.errors)).unwrap_or_else(util::log_error);
+        }
+        Action::FinishRead => {
+          spi.deselect();
+
+          // received() never exceeds the capacity of Bytes
+          let data = Bytes::from_slice(spi.frame.received()).unwrap();
+          spi.finish(Ok(Response::Read(data)));
+        }
+        Action::FinishWrite => {
+          spi.deselect();
+          spi.finish(Ok(Response::Written));
+        }
+        Action::SetTransferMode(mode) => {
+          spi.transfer_mode = mode;
+          spi.benchmark.alternate = false;
+        }
+        Action::ReportUsage => {
+          let usage = &spi.benchmark;
+          debugger::print(format_args!("SPI1 interrupt mode: {} transactions, {:?} cycles each",
+            usage.interrupt.transactions, usage.interrupt.cycles_per_transaction()));
+          debugger::print(format_args!("SPI1 DMA mode: {} transactions, {:?} cycles each",
+            usage.dma.transactions, usage.dma.cycles_per_transaction()));
+        }
+        Action::Reject => {
+          debugger::print(format_args!("SPI1 ignored {:?} from {:?}", x, source));
+        }
+        Action::Reset => {
+          // nothing was on the bus, whatever the driver still held is let go
+          spi.release();
+          spi.start_next();
+        }
+        Action::DoNothing => (),
       }
-      _ => ()
     }
+
+    spi.charge(start);
   });
 }
 
+/// Reports the outcome of a transaction to the task that requested it
+fn reply(to: Requester, completion: Completion) {
+  util::reply(Task::Spi1, &to, app::Message::SpiResult(completion)).unwrap_or_else(util::log_error);
+}
 
-
-impl State {
-  pub fn next(self, msg: &Message) -> (State, Action) {
-    match (self, msg) {
-      (State::Idling, Message::StartRead(r)) => {
-        (State::Reading, Action::StartRead(*r))
-      }
-      (State::Idling, Message::StartWrite(w)) => {
-        (State::Writing, Action::StartWrite(*w))
-      }
-      (State::Reading, Message::RxEvent) => {
-        (State::Reading, Action::ContinueRead)
-      }
-      (State::Writing, Message::RxEvent) => {
-        (State::Writing, Action::ContinueWrite)
-      }
-      (State::Reading, Message::FinishTransaction) => {
-        (State::WaitingForConfirmation, Action::DoNothing)
-      }
-      (State::Writing, Message::FinishTransaction) => {
-        (State::WaitingForConfirmation, Action::DoNothing)
-      }
-      (State::WaitingForConfirmation, Message::ReadConfirmation) => {
-        (State::Idling, Action::DoNothing)
-      }
-      (State::WaitingForConfirmation, Message::WriteConfirmation) => {
-        (State::Idling, Action::DoNothing)
-      }
-      (_s, Message::CancelTransaction) => {
-        (State::Idling, Action::Reset)
-      }
-      (s, _m) => {
-        (s, Action::Reject)
-      }
-    }
+fn bus_error(e: nb::Error<spi::Error>) -> Error {
+  match e {
+    nb::Error::Other(spi::Error::ModeFault) => Error::ModeFault,
+    nb::Error::Other(spi::Error::Crc) => Error::Crc,
+    // no byte after an RXNE event means the receiver fell behind
+    _ => Error::Overrun,
   }
 }
 
+
+
 impl<T, U> Data<T, U> {
-  pub fn new(spi: T, cs_pin: U) -> Self {
+  pub fn new(spi: T, cs_pins: [U; DEVICE_COUNT], mode: Mode, transfer_mode: TransferMode) -> Self {
     Data {
       spi,
-      cs_pin,
+      cs_pins,
+      mode,
       state: State::Idling,
-      origin: Task::Init,
-      tx_buffer: [0; TX_BUFFER_SIZE],
-      rx_buffer: [0; RX_BUFFER_SIZE],
-      transfer_len: 0,
-      bytes_transferred: 0
+      id: 0,
+      device: Device::Lis3dsh,
+      origin: Requester { task: Task::Init, id: 0 },
+      deadline: Deadline::new(),
+      timeout: None,
+      queue: Queue::new(),
+      transfer_mode,
+      transfer: Transfer::new(),
+      frame: Frame::new(),
+      benchmark: Benchmark::new(),
+      errors: ErrorCounters::default(),
+    }
+  }
+
+  /// Adds the cycles since `start` to the running transfer mode
+  pub fn charge(&mut self, start: util::Instant) {
+    let cycles = util::cycles_since(start);
+    self.benchmark.get_mut(self.transfer_mode).add(cycles);
+  }
+}
+
+impl<PINS, U> Data<spi::Spi<SPI1, PINS>, U>
+  where U: OutputPin<Error = Infallible>
+{
+  /// Puts a request on the bus, the state machine has to be in `Reading` or `Writing` already
+  fn start(&mut self, origin: Requester, request: Request) {
+    self.origin = origin;
+    self.id = request.id();
+    self.device = request.device();
+    self.set_mode(self.device.mode());
+
+    // the deadline covers the time on the bus, not the time spent in the queue
+    let generation = self.deadline.arm();
+    let msg = app::Message::Spi(Message::Timeout(generation));
+    self.timeout = util::schedule_message(Task::Spi1, &Task::Spi1, msg, Micros(timeout_us(request.bytes())))
+      .map_err(util::log_error)
+      .ok();
+
+    let cs_pin = &mut self.cs_pins[self.device.index()];
+    match self.transfer_mode {
+      TransferMode::Interrupt => {
+        self.spi.listen(spi::Event::Rxne);
+        let started = match request {
+          Request::Read(_, r) => self.transfer.start_read(&mut self.spi, cs_pin, r),
+          Request::Write(_, w) => self.transfer.start_write(&mut self.spi, cs_pin, w),
+        };
+
+        if let Err(e) = started {
+          self.fail(bus_error(e));
+        }
+      }
+      TransferMode::Dma => {
+        let len = match request {
+          Request::Read(_, r) => self.frame.load_read(r),
+          Request::Write(_, w) => self.frame.load_write(w),
+        };
+
+        cs_pin.set_low().unwrap();
+        self.start_dma(len);
+      }
+    }
+  }
+
+  /// Hands the result to the originator and moves on to the next request
+  fn finish(&mut self, result: Result<Response, Error>) {
+    let action;
+    (self.state, action) = self.state.next(&Message::FinishTransaction, &self.deadline);
+    self.disarm();
+    self.transfer_mode = self.benchmark.finish(self.transfer_mode);
+
+    reply(self.origin, Completion { id: self.id, device: self.device, result });
+
+    if let Action::StartNext = action {
+      self.start_next();
+    }
+  }
+
+  /// Error detected by the driver itself rather than the interrupt handler
+  fn fail(&mut self, e: Error) {
+    (self.state, ..) = self.state.next(&Message::Error(e), &self.deadline);
+    self.abort(e);
+  }
+
+  fn count_error(&mut self, e: Error) {
+    let total = self.errors.total();
+    self.errors.record(e);
+
+    if self.errors.total() != total {
+      heartbeat::report(Task::Spi1, Status::SpiErrors(self.errors.total()));
+    }
+  }
+
+  /// Gives up on the transaction on the bus after a fault
+  fn abort(&mut self, e: Error) {
+    debugger::print(format_args!("SPI1 transaction {} failed: {:?}", self.id, e));
+    self.count_error(e);
+    self.release();
+    self.transfer_mode = self.benchmark.finish(self.transfer_mode);
+
+    reply(self.origin, Completion { id: self.id, device: self.device, result: Err(e) });
+    self.start_next();
+  }
+
+  /// Stops whatever is moving bytes and deselects the device
+  fn release(&mut self) {
+    self.disarm();
+    self.stop_dma();
+    self.spi.unlisten(spi::Event::Rxne);
+    self.transfer.reset();
+    self.deselect();
+  }
+
+  fn deselect(&mut self) {
+    self.cs_pins[self.device.index()].set_high().unwrap();
+  }
+
+  /// The deadline alone would already ignore a stale timeout, cancelling it
+  /// saves the mailbox slot and the wakeup
+  fn disarm(&mut self) {
+    self.deadline.disarm();
+    if let Some(timeout) = self.timeout.take() {
+      timeout.cancel();
+    }
+  }
+
+  /// Checks and clears the error flags, called from the SPI1 interrupt
+  pub fn take_error(&mut self) -> Option<Error> {
+    let regs = unsafe { &*SPI1::ptr() };
+    let sr = regs.sr.read();
+
+    if sr.ovr().bit_is_set() {
+      // cleared by reading DR followed by SR
+      let _ = regs.dr.read();
+      let _ = regs.sr.read();
+      Some(Error::Overrun)
+    } else if sr.modf().bit_is_set() {
+      // cleared by a write to CR1, the fault also dropped us out of master mode
+      regs.cr1.modify(|_, w| w.mstr().set_bit().spe().set_bit());
+      Some(Error::ModeFault)
+    } else if sr.crcerr().bit_is_set() {
+      regs.sr.modify(|_, w| w.crcerr().clear_bit());
+      Some(Error::Crc)
+    } else {
+      None
+    }
+  }
+
+  fn start_next(&mut self) {
+    if let Some(pending) = self.queue.pop() {
+      let action;
+      (self.state, action) = self.state.next(&pending.request.into(), &self.deadline);
+
+      match action {
+        Action::StartRead(..) | Action::StartWrite(..) => self.start(pending.origin, pending.request),
+        _ => reply(pending.origin, Completion::rejected(&pending.request)),
+      }
+    }
+  }
+
+  /// Moves the loaded frame, DMA2_STREAM0 fires once the last byte came in
+  fn start_dma(&mut self, len: usize) {
+    let dma = unsafe { &*DMA2::ptr() };
+    let regs = unsafe { &*SPI1::ptr() };
+    let dr = &regs.dr as *const _ as u32;
+
+    Self::clear_dma_flags(dma);
+
+    let rx = &dma.st[RX_STREAM];
+    // the addresses stay valid, the frame lives in a shared resource
+    rx.par.write(|w| unsafe { w.pa().bits(dr) });
+    rx.m0ar.write(|w| unsafe { w.m0a().bits(self.frame.rx_ptr() as u32) });
+    rx.ndtr.write(|w| w.ndt().bits(len as u16));
+    rx.fcr.modify(|_, w| w.feie().set_bit());
+    rx.cr.write(|w| {
+      w.chsel().bits(DMA_CHANNEL)
+        .dir().peripheral_to_memory()
+        .minc().set_bit()
+        .tcie().set_bit()
+        .teie().set_bit()
+        .dmeie().set_bit()
+        .en().set_bit()
+    });
+
+    let tx = &dma.st[TX_STREAM];
+    tx.par.write(|w| unsafe { w.pa().bits(dr) });
+    tx.m0ar.write(|w| unsafe { w.m0a().bits(self.frame.tx_ptr() as u32) });
+    tx.ndtr.write(|w| w.ndt().bits(len as u16));
+    // DMA2_STREAM3 only fires on errors
+    tx.fcr.modify(|_, w| w.feie().set_bit());
+    tx.cr.write(|w| {
+      w.chsel().bits(DMA_CHANNEL)
+        .dir().memory_to_peripheral()
+        .minc().set_bit()
+        .teie().set_bit()
+        .dmeie().set_bit()
+        .en().set_bit()
+    });
+
+    // the first TXE request starts the transfer
+    regs.cr2.modify(|_, w| w.rxdmaen().set_bit().txdmaen().set_bit());
+  }
+
+  /// Checks the error flags of both streams, called from the DMA interrupts
+  /// before `stop_dma` clears them
+  pub fn take_dma_error(&self) -> Option<Error> {
+    let isr = unsafe { &*DMA2::ptr() }.lisr.read();
+
+    let rx = isr.teif0().bit_is_set() || isr.dmeif0().bit_is_set() || isr.feif0().bit_is_set();
+    let tx = isr.teif3().bit_is_set() || isr.dmeif3().bit_is_set() || isr.feif3().bit_is_set();
+    if rx || tx {
+      Some(Error::Dma)
+    } else {
+      None
+    }
+  }
+
+  /// Called from the DMA interrupt and when a transaction is cancelled
+  pub fn stop_dma(&mut self) {
+    let dma = unsafe { &*DMA2::ptr() };
+    let regs = unsafe { &*SPI1::ptr() };
+
+    regs.cr2.modify(|_, w| w.rxdmaen().clear_bit().txdmaen().clear_bit());
+    dma.st[RX_STREAM].cr.modify(|_, w| w.en().clear_bit());
+    dma.st[TX_STREAM].cr.modify(|_, w| w.en().clear_bit());
+    Self::clear_dma_flags(dma);
+  }
+
+  fn clear_dma_flags(dma: &stm32f4xx_hal::pac::dma2::RegisterBlock) {
+    dma.lifcr.write(|w| {
+      w.ctcif0().set_bit().chtif0().set_bit().cteif0().set_bit().cdmeif0().set_bit().cfeif0().set_bit()
+        .ctcif3().set_bit().chtif3().set_bit().cteif3().set_bit().cdmeif3().set_bit().cfeif3().set_bit()
+    });
+  }
+
+  fn set_mode(&mut self, mode: Mode) {
+    if mode == self.mode {
+      return;
     }
+
+    // the HAL only sets the clock polarity and phase on creation. the bus is
+    // idle between transactions so the peripheral can be switched off safely
+    let regs = unsafe { &*SPI1::ptr() };
+    regs.cr1.modify(|_, w| w.spe().clear_bit());
+    regs.cr1.modify(|_, w| {
+      w.cpha().bit(mode.phase == Phase::CaptureOnSecondTransition)
+        .cpol().bit(mode.polarity == Polarity::IdleHigh)
+        .spe().set_bit()
+    });
+
+    self.mode = mode;
   }
-}
\ No newline at end of file
+}
@@ -2,16 +2,42 @@ use crate::constants;
 use crate::app::{
   lis_mb_app,
   heartbeat_mb_app,
+  leds_mb_app,
   spi1_mb_app,
   button_mb_app,
-  Task,
+  retry_app,
   MessagePacket
 };
-use rtic::cyccnt::{Instant, U32Ext};
 use crate::app;
+use crate::app::monotonics;
+use dwt_systick_monotonic::fugit;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU16, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use stm32f4_core::trace::Event;
+use stm32f4_core::mailbox::{Overflow, Outcome, Mailbox};
+use stm32f4_core::time::{TimeUnit, Micros, Millis};
 
-pub const fn convert_us_to_cycles(us: u32) -> u32 {
-  us * (constants::CPU_FREQ / 1_000_000)
+/// Time as kept by the monotonic, in CPU cycles
+pub type Instant = fugit::TimerInstantU32<{ constants::CPU_FREQ }>;
+pub type Duration = fugit::TimerDurationU32<{ constants::CPU_FREQ }>;
+
+/// `delay` as a monotonic duration, `None` when it is too long to count
+pub fn duration(delay: impl TimeUnit) -> Option<Duration> {
+  delay.to_cycles(constants::CPU_FREQ).map(Duration::from_ticks)
+}
+
+/// For fixed delays, a `const` that does not fit fails the build
+pub const fn millis(ms: u32) -> Duration {
+  match Millis(ms).to_cycles(constants::CPU_FREQ) {
+    Some(cycles) => Duration::from_ticks(cycles),
+    None => panic!("delay too long for the cycle counter"),
+  }
+}
+
+/// Cycles spent since `start`, 0 if it is still to come
+pub fn cycles_since(start: Instant) -> u32 {
+  monotonics::now().checked_duration_since(start).map_or(0, |d| d.ticks())
 }
 
 pub mod debugger {
@@ -32,81 +58,428 @@ pub mod debugger {
 
   pub fn print(s: core::fmt::Arguments) {
     if unsafe { ENABLED } {
-      hprintln!("{}", s).unwrap();
+      hprintln!("{}", s);
+    }
+  }
+}
+
+/// Generates `Task` and the router from one list of mailboxes. Each entry is
+/// the task, the RTIC software task that holds its mailbox and what happens
+/// to messages once it is full. Tasks listed as senders have no mailbox.
+macro_rules! mailboxes {
+  (
+    senders: [$($sender:ident),* $(,)?],
+    $($task:ident => $mailbox:ident(overflow = $overflow:expr)),* $(,)?
+  ) => {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Task {
+      $($sender,)*
+      $($task,)*
+    }
+
+    const TASK_COUNT: usize = [$(stringify!($sender),)* $(stringify!($task),)*].len();
+
+    impl Task {
+      pub const fn overflow(self) -> Overflow {
+        match self {
+          $(Task::$sender => Overflow::DropNewest,)*
+          $(Task::$task => $overflow,)*
+        }
+      }
+
+      pub const ALL: [Task; TASK_COUNT] = [$(Task::$sender,)* $(Task::$task,)*];
+
+      pub const fn name(self) -> &'static str {
+        match self {
+          $(Task::$sender => stringify!($sender),)*
+          $(Task::$task => stringify!($task),)*
+        }
+      }
     }
+
+    fn spawn(dest: &Task, packet: MessagePacket) -> Result<(), RticError> {
+      match dest {
+        $(Task::$task => $mailbox::spawn(packet).map_err(RticError::Spawn),)*
+        $(Task::$sender)|* => Err(RticError::Undeliverable(packet)),
+      }
+    }
+
+    fn schedule(dest: &Task, at: Instant, packet: MessagePacket) -> Result<SpawnHandle, RticError> {
+      match dest {
+        $(Task::$task => $mailbox::spawn_at(at, packet).map(SpawnHandle::$task).map_err(RticError::Schedule),)*
+        $(Task::$sender)|* => Err(RticError::Undeliverable(packet)),
+      }
+    }
+
+    /// RTIC's handle to a message in the timer queue, one type per task
+    #[derive(Debug)]
+    enum SpawnHandle {
+      $($task($mailbox::SpawnHandle),)*
+      // parked in `retry_app` until it is due
+      Retry(retry_app::SpawnHandle),
+    }
+
+    impl SpawnHandle {
+      /// Takes the message out of the timer queue, which frees its slot in the
+      /// task's capacity. Fails once the message is due.
+      fn cancel(self) -> bool {
+        match self {
+          $(SpawnHandle::$task(handle) => handle.cancel().is_ok(),)*
+          SpawnHandle::Retry(handle) => handle.cancel().is_ok(),
+        }
+      }
+    }
+  };
+}
+
+// the capacity of each mailbox is set on its `#[task]` in main.rs
+mailboxes! {
+  senders: [Init, Interrupt],
+  // a lost toggle leaves the heartbeat in the wrong state, a lost report
+  // leaves it showing an old blink code
+  Heartbeat => heartbeat_mb_app(overflow = Overflow::Retry { delay_us: 1_000, attempts: 3 }),
+  // a lost command leaves an LED in the wrong effect
+  Leds => leds_mb_app(overflow = Overflow::Retry { delay_us: 1_000, attempts: 3 }),
+  // a lost gesture timer leaves the button stuck mid-gesture, repeated presses
+  // are ignored by the button
+  Button => button_mb_app(overflow = Overflow::Retry { delay_us: 1_000, attempts: 3 }),
+  // a lost request or bus event leaves a client waiting for its completion
+  Spi1 => spi1_mb_app(overflow = Overflow::Retry { delay_us: 100, attempts: 3 }),
+  Lis3dsh => lis_mb_app(overflow = Overflow::Retry { delay_us: 500, attempts: 3 }),
+}
+
+// router bookkeeping, shared by every priority
+static MAILBOXES: Mutex<RefCell<[Mailbox<MessagePacket>; TASK_COUNT]>> = {
+  const EMPTY: Mailbox<MessagePacket> = Mailbox::new();
+  Mutex::new(RefCell::new([EMPTY; TASK_COUNT]))
+};
+
+pub use stm32f4_core::mailbox::CorrelationId;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Fresh id for a request, every message gets one
+pub fn next_id() -> CorrelationId {
+  NEXT_ID.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+}
+
+/// Where the reply to a request goes and the id it has to carry
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Requester {
+  pub task: Task,
+  pub id: CorrelationId,
+}
+
+impl MessagePacket {
+  pub fn new(source: Task, msg: app::Message) -> Self {
+    MessagePacket::with_id(source, next_id(), msg)
+  }
+
+  /// For requests that have to be told apart by the id they were sent with,
+  /// and for replies carrying the id of their request
+  pub fn with_id(source: Task, id: CorrelationId, msg: app::Message) -> Self {
+    MessagePacket { source, id, reply_to: None, timer: None, msg }
+  }
+
+  /// Sends the replies to `task` instead of the source
+  pub fn reply_to(self, task: Task) -> Self {
+    MessagePacket { reply_to: Some(task), ..self }
+  }
+
+  pub fn requester(&self) -> Requester {
+    Requester { task: self.reply_to.unwrap_or(self.source), id: self.id }
   }
 }
 
 pub fn send_message(source: Task, dest: &Task, msg: app::Message) -> Result<(), RticError> {
-  match dest {
-    Task::Lis3dsh => {
-      lis_mb_app::spawn(MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Spawn(e))?;
+  send_packet(dest, MessagePacket::new(source, msg))
+}
+
+pub fn send_packet(dest: &Task, packet: MessagePacket) -> Result<(), RticError> {
+  deliver(dest, packet, 0)
+}
+
+/// Handle to a scheduled message that may not have been delivered yet
+#[derive(Debug)]
+pub struct Scheduled {
+  dest: Task,
+  id: CorrelationId,
+  due: Instant,
+  handle: SpawnHandle,
+}
+
+impl Scheduled {
+  pub fn due(&self) -> Instant {
+    self.due
+  }
+
+  /// Returns false when the message has already been delivered. A message
+  /// that is due but still queued is dropped when it arrives, unless the
+  /// mailbox had too many scheduled messages to track it.
+  pub fn cancel(self) -> bool {
+    let Scheduled { dest, id, handle, .. } = self;
+
+    with_mailbox(&dest, |mailbox| {
+      if handle.cancel() {
+        mailbox.timers.remove(id);
+        true
+      } else {
+        mailbox.timers.cancel(id)
+      }
+    })
+  }
+
+  /// Cancels the pending message and schedules `msg` in its place
+  pub fn reschedule(self, source: Task, msg: app::Message, delay: impl TimeUnit) -> Result<Scheduled, RticError> {
+    let dest = self.dest;
+    self.cancel();
+    schedule_message(source, &dest, msg, delay)
+  }
+
+  /// Schedules `msg` `period` after this message was due, so periodic
+  /// messages keep their cadence however long they took to handle
+  pub fn repeat(self, source: Task, msg: app::Message, period: impl TimeUnit) -> Result<Scheduled, RticError> {
+    let (dest, due) = (self.dest, self.due);
+    self.cancel();
+
+    let packet = MessagePacket::new(source, msg);
+    match duration(period) {
+      Some(period) => schedule_packet_at(&dest, packet, due + period),
+      None => Err(out_of_range(&dest, packet)),
     }
-    Task::Heartbeat => {
-      heartbeat_mb_app::spawn(MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Spawn(e))?;
+  }
+}
+
+pub fn schedule_message(source: Task, dest: &Task, msg: app::Message, delay: impl TimeUnit) -> Result<Scheduled, RticError> {
+  schedule_packet(dest, MessagePacket::new(source, msg), delay)
+}
+
+pub fn schedule_packet(dest: &Task, packet: MessagePacket, delay: impl TimeUnit) -> Result<Scheduled, RticError> {
+  match duration(delay) {
+    Some(delay) => schedule_packet_at(dest, packet, monotonics::now() + delay),
+    None => Err(out_of_range(dest, packet)),
+  }
+}
+
+/// A time already passed delivers the message right away
+pub fn schedule_packet_at(dest: &Task, mut packet: MessagePacket, sched_time: Instant) -> Result<Scheduled, RticError> {
+  let id = packet.id;
+  let source = packet.source;
+  let label = message_name(&packet.msg);
+
+  // tracked before it is scheduled, it could arrive right away
+  packet.timer = Some(id);
+  with_mailbox(dest, |mailbox| mailbox.timers.add(id));
+
+  let result = schedule(dest, sched_time, packet);
+  trace::record(trace_event(&result, Event::Schedule), source, dest, label);
+
+  let result = match result {
+    // waits in the retry task instead, the overflow policy applies once it is due
+    Err(RticError::Schedule(packet)) => park(dest, sched_time, packet, 0),
+    r => r,
+  };
+
+  match result {
+    Ok(handle) => Ok(Scheduled { dest: *dest, id, due: sched_time, handle }),
+    Err(e) => {
+      with_mailbox(dest, |mailbox| mailbox.timers.remove(id));
+      Err(e)
     }
-    Task::Button => {
-      button_mb_app::spawn(MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Spawn(e))?;
+  }
+}
+
+fn out_of_range(dest: &Task, packet: MessagePacket) -> RticError {
+  trace::record(Event::ScheduleFailed, packet.source, dest, message_name(&packet.msg));
+  RticError::OutOfRange(packet)
+}
+
+fn deliver(dest: &Task, packet: MessagePacket, attempt: u8) -> Result<(), RticError> {
+  let source = packet.source;
+  let label = message_name(&packet.msg);
+
+  let result = spawn(dest, packet);
+  trace::record(trace_event(&result, Event::Send), source, dest, label);
+
+  match result {
+    Err(RticError::Spawn(packet)) => overflow(dest, packet, attempt),
+    r => r,
+  }
+}
+
+fn overflow(dest: &Task, packet: MessagePacket, attempt: u8) -> Result<(), RticError> {
+  match dest.overflow().resolve(attempt) {
+    Outcome::Drop => {
+      drop_one(dest);
+      Err(RticError::Dropped(*dest))
     }
-    Task::Spi1 => {
-      spi1_mb_app::spawn(MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Spawn(e))?;
+    Outcome::Displace => {
+      with_mailbox(dest, |mailbox| mailbox.displace(packet));
+      Ok(())
     }
-    Task::Init => (),
-    Task::Interrupt => (),
+    Outcome::RetryIn(delay_us) => match duration(Micros(delay_us)) {
+      Some(delay) => park(dest, monotonics::now() + delay, packet, attempt + 1).map(drop),
+      None => {
+        drop_one(dest);
+        Err(RticError::Dropped(*dest))
+      }
+    },
   }
+}
+
+fn park(dest: &Task, at: Instant, packet: MessagePacket, attempt: u8) -> Result<SpawnHandle, RticError> {
+  retry_app::spawn_at(at, *dest, packet, attempt).map(SpawnHandle::Retry).map_err(|_| {
+    drop_one(dest);
+    RticError::Dropped(*dest)
+  })
+}
 
-  Ok(())
+/// Runs in `retry_app` once a parked message is due
+pub fn retry(dest: Task, packet: MessagePacket, attempt: u8) {
+  // nobody is left to tell
+  let _ = deliver(&dest, packet, attempt);
 }
 
-pub fn schedule_message(source: Task, dest: &Task, msg: app::Message, micros_from_now: u32) -> Result<(), RticError> {
-  let sched_time = Instant::now() + convert_us_to_cycles(micros_from_now).cycles();
+/// Every mailbox task passes what it dequeued through here first. Gives `None`
+/// when a newer message displaced it or when it was cancelled.
+pub fn receive(task: Task, packet: MessagePacket) -> Option<MessagePacket> {
+  let (cancelled, displaced) = with_mailbox(&task, |mailbox| {
+    (!mailbox.timers.admit(packet.timer), mailbox.take_displaced())
+  });
 
-  match dest {
-    Task::Lis3dsh => {
-      lis_mb_app::schedule(sched_time, MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Schedule(e))?;
+  match displaced {
+    Some(newest) => {
+      // the dequeue made room for it
+      let _ = deliver(&task, newest, 0);
+      None
     }
-    Task::Heartbeat => {
-      heartbeat_mb_app::schedule(sched_time, MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Schedule(e))?;
+    None if cancelled => None,
+    None => Some(packet),
+  }
+}
+
+/// Messages a task lost to a full mailbox since boot
+pub fn drops(task: Task) -> u32 {
+  with_mailbox(&task, |mailbox| mailbox.drops())
+}
+
+/// Messages lost by the mailboxes that retry rather than drop, these are the
+/// ones a task was waiting for
+pub fn lost() -> u32 {
+  Task::ALL.iter()
+    .filter(|task| matches!(task.overflow(), Overflow::Retry { .. }))
+    .fold(0, |lost, task| lost.wrapping_add(drops(*task)))
+}
+
+fn drop_one(task: &Task) {
+  with_mailbox(task, |mailbox| mailbox.drop_one());
+}
+
+fn with_mailbox<R>(task: &Task, f: impl FnOnce(&mut Mailbox<MessagePacket>) -> R) -> R {
+  interrupt::free(|cs| f(&mut MAILBOXES.borrow(cs).borrow_mut()[*task as usize]))
+}
+
+/// For senders with nothing better to do with a message that was not delivered
+pub fn log_error(e: RticError) {
+  match e {
+    RticError::Dropped(task) => {
+      debugger::print(format_args!("{:?} mailbox full, {} messages dropped", task, drops(task)));
+    }
+    RticError::Undeliverable(packet) => {
+      debugger::print(format_args!("no mailbox for {:?} from {:?}", packet.msg, packet.source));
     }
-    Task::Button => {
-      button_mb_app::schedule(sched_time, MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Schedule(e))?;
+    RticError::OutOfRange(packet) => {
+      debugger::print(format_args!("{:?} from {:?} scheduled too far ahead", packet.msg, packet.source));
     }
-    Task::Spi1 => {
-      spi1_mb_app::schedule(sched_time, MessagePacket {
-        source,
-        msg
-      }).map_err(|e| RticError::Schedule(e))?;
+    e => debugger::print(format_args!("message not delivered: {:?}", e)),
+  }
+}
+
+/// For answers to requests, they carry the id of the request. Tasks without a
+/// mailbox, like `Init`, can still make requests, their answers are dropped
+pub fn reply(source: Task, to: &Requester, msg: app::Message) -> Result<(), RticError> {
+  let packet = MessagePacket::with_id(source, to.id, msg);
+
+  match send_packet(&to.task, packet) {
+    Err(RticError::Undeliverable(_)) => Ok(()),
+    r => r,
+  }
+}
+
+/// Outer variant of a message and the variant of the module's message it
+/// wraps, all the trace keeps of it
+pub fn message_name(msg: &app::Message) -> (&'static str, Option<&'static str>) {
+  match msg {
+    app::Message::Lis3dsh(m) => ("Lis3dsh", Some(m.name())),
+    app::Message::Heartbeat(m) => ("Heartbeat", Some(m.name())),
+    app::Message::Leds(m) => ("Leds", Some(m.name())),
+    app::Message::Button(m) => ("Button", Some(m.name())),
+    app::Message::Spi(m) => ("Spi", Some(m.name())),
+    app::Message::SpiResult(completion) => ("SpiResult", Some(completion.name())),
+    app::Message::SpiErrors(_) => ("SpiErrors", None),
+    app::Message::AccelSample { .. } => ("AccelSample", None),
+    app::Message::DeviceId(_) => ("DeviceId", None),
+  }
+}
+
+fn trace_event<T>(result: &Result<T, RticError>, routed: Event) -> Event {
+  match result {
+    Ok(_) => routed,
+    Err(RticError::Spawn(_)) | Err(RticError::Dropped(_)) => Event::SpawnFailed,
+    Err(RticError::Schedule(_)) | Err(RticError::OutOfRange(_)) => Event::ScheduleFailed,
+    Err(RticError::Undeliverable(_)) => Event::Undeliverable,
+  }
+}
+
+/// Every routed message ends up in a ring buffer when built with the `trace`
+/// feature. `dump` prints it for `trace_decode` in stm32f4-core.
+#[cfg(feature = "trace")]
+pub mod trace {
+  use core::cell::RefCell;
+  use cortex_m::interrupt::{self, Mutex};
+  use stm32f4_core::trace::{Trace, Record, Event};
+  use super::{Task, debugger};
+
+  // every priority routes messages
+  static TRACE: Mutex<RefCell<Trace>> = Mutex::new(RefCell::new(Trace::new()));
+
+  pub fn record(event: Event, source: Task, dest: &Task, (message, variant): (&'static str, Option<&'static str>)) {
+    let timestamp = cortex_m::peripheral::DWT::cycle_count();
+    let record = Record { timestamp, event, source: source.name(), dest: dest.name(), message, variant };
+
+    interrupt::free(|cs| TRACE.borrow(cs).borrow_mut().record(record));
+  }
+
+  /// Prints a copy of the ring, messages routed meanwhile are not held up
+  /// by the semihosting. Call it with no lock held.
+  pub fn dump() {
+    let trace = interrupt::free(|cs| TRACE.borrow(cs).borrow().clone());
+
+    debugger::print(format_args!("trace: {} records, {} lost", trace.len(), trace.lost()));
+    for record in trace.iter() {
+      debugger::print(format_args!("{}", record));
     }
-    Task::Init => (),
-    Task::Interrupt => (),
   }
+}
+
+#[cfg(not(feature = "trace"))]
+pub mod trace {
+  use stm32f4_core::trace::Event;
+  use super::Task;
+
+  pub fn record(_event: Event, _source: Task, _dest: &Task, _message: (&'static str, Option<&'static str>)) {}
 
-  Ok(())
+  pub fn dump() {}
 }
 
 #[derive(Debug)]
 pub enum RticError {
   Spawn(app::MessagePacket),
   Schedule(app::MessagePacket),
+  // the destination has no mailbox
+  Undeliverable(app::MessagePacket),
+  // the destination's mailbox was full, the message is counted and gone
+  Dropped(Task),
+  // the delay does not fit the cycle counter, the message was not scheduled
+  OutOfRange(app::MessagePacket),
 }
@@ -0,0 +1,121 @@
+//! Turns a trace dump from the debug console into a sequence diagram.
+//!
+//! Reads the log on stdin and draws the last N messages (all by default):
+//!
+//!     cargo run --bin trace_decode -- 20 < openocd.log
+
+use std::env;
+use std::io::{self, Read};
+
+use stm32f4_core::trace::{self, Record};
+
+// the firmware runs the core at 168MHz, CYCCNT counts core cycles
+const CYCLES_PER_US: u32 = 168;
+const COLUMN_WIDTH: usize = 12;
+const TIME_WIDTH: usize = 12;
+
+
+fn main() {
+  let last = env::args().nth(1).map(|n| n.parse().expect("N has to be a number"));
+
+  let mut log = String::new();
+  io::stdin().read_to_string(&mut log).expect("failed to read stdin");
+
+  let records: Vec<Record> = log.lines().filter_map(trace::parse).collect();
+  let skip = last.map_or(0, |n: usize| records.len().saturating_sub(n));
+
+  print!("{}", render(&records[skip..]));
+}
+
+/// Draws one lifeline per task in order of appearance, one arrow per record.
+/// Times are in microseconds since the first record.
+fn render(records: &[Record]) -> String {
+  let mut tasks: Vec<&str> = Vec::new();
+  for r in records {
+    for name in [r.source, r.dest].iter() {
+      if !tasks.contains(name) {
+        tasks.push(name);
+      }
+    }
+  }
+
+  let mut out = format!("{:>w$}  ", "time (us)", w = TIME_WIDTH);
+  for task in tasks.iter() {
+    out += &format!("{:<w$}", task, w = COLUMN_WIDTH);
+  }
+  out = out.trim_end().to_string() + "\n";
+
+  let start = records.first().map_or(0, |r| r.timestamp);
+  for r in records {
+    let from = tasks.iter().position(|t| *t == r.source).unwrap() * COLUMN_WIDTH;
+    let to = tasks.iter().position(|t| *t == r.dest).unwrap() * COLUMN_WIDTH;
+
+    let mut line: Vec<char> = vec![' '; tasks.len() * COLUMN_WIDTH];
+    for i in 0..tasks.len() {
+      line[i * COLUMN_WIDTH] = '|';
+    }
+
+    if from == to {
+      line[from] = 'o';
+    } else {
+      let (left, right) = if from < to { (from, to) } else { (to, from) };
+      for c in line[left + 1..right].iter_mut() {
+        *c = '-';
+      }
+      line[to] = if from < to { '>' } else { '<' };
+    }
+
+    let message = match r.variant {
+      Some(variant) => format!("{}::{}", r.message, variant),
+      None => r.message.to_string(),
+    };
+    let label = if r.event.is_failure() {
+      format!("{} ({})", message, r.event.name())
+    } else {
+      message
+    };
+
+    // CYCCNT wraps every ~25s at 168MHz
+    let us = r.timestamp.wrapping_sub(start) / CYCLES_PER_US;
+    let line: String = line.into_iter().collect();
+    out += &format!("{:>w$}  {}  {}\n", us, line.trim_end(), label, w = TIME_WIDTH);
+  }
+
+  out
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn arrows_point_at_the_destination() {
+    let log = "\
+boot
+TRACE 0 send Lis3dsh Spi1 Spi::StartRead
+TRACE 1680 send Spi1 Lis3dsh SpiResult::Read
+TRACE 3360 spawn-failed Interrupt Spi1 Spi::RxEvent
+";
+    let records: Vec<Record> = log.lines().filter_map(trace::parse).collect();
+
+    let diagram = render(&records);
+    let lines: Vec<&str> = diagram.lines().collect();
+
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].ends_with("Lis3dsh     Spi1        Interrupt"));
+    assert!(lines[1].contains("|----------->           |  Spi::StartRead"));
+    assert!(lines[2].trim_start().starts_with("10  <-----------|"));
+    assert!(lines[3].ends_with("Spi::RxEvent (spawn-failed)"));
+  }
+
+  #[test]
+  fn messages_to_self_are_marked() {
+    let log = "TRACE 0 schedule Lis3dsh Lis3dsh Lis3dsh::StreamTick";
+    let records: Vec<Record> = log.lines().filter_map(trace::parse).collect();
+
+    let diagram = render(&records);
+
+    assert!(diagram.lines().nth(1).unwrap().contains("o  Lis3dsh::StreamTick"));
+  }
+}
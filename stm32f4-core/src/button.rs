@@ -0,0 +1,411 @@
+pub const SAMPLE_SIZE: usize = 10;
+const SAMPLE_THRESHOLD: usize = 5;
+
+#[derive(Debug)]
+pub enum Message {
+  ButtonPressed,
+  ButtonNotPressed,
+  /// Gestures go to the sender from now on
+  Subscribe,
+  Configure(Timing),
+  /// Scheduled by the recognizer, carries the generation of the press it
+  /// belongs to so a stale one can be told apart
+  Timer(Timer, u16),
+  /// What subscribers get
+  Gesture(Gesture),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Gesture {
+  /// Released before it was a long press, and not pressed again within the
+  /// double click window. Comes once the window has closed.
+  ShortPress,
+  LongPress,
+  DoubleClick,
+  /// While held past a long press, counts from 1
+  Repeat(u16),
+  /// Every release, whatever the gesture
+  Released,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Timer {
+  LongPress,
+  ClickWindow,
+  Repeat,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Timing {
+  /// Held this long it is a long press
+  pub long_press_ms: u32,
+  /// Pressed again within this long after a release it is a double click
+  pub double_click_ms: u32,
+  /// Between two repeats while held
+  pub repeat_ms: u32,
+}
+
+/// What the recognizer wants done about an input
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Reaction {
+  pub gesture: Option<Gesture>,
+  /// Replaces any timer still pending
+  pub timer: Option<(Timer, u32)>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Phase {
+  Idle,
+  // down, not for long enough to be a long press yet
+  Down,
+  // down past a long press, repeating
+  Held,
+  // released after a short press, waiting for another one
+  Up,
+  // down again within the window
+  SecondDown,
+}
+
+/// Turns debounced presses and releases into gestures. Time comes from the
+/// timers it asks for, it keeps no clock of its own.
+pub struct Recognizer {
+  pub timing: Timing,
+  phase: Phase,
+  generation: u16,
+  repeats: u16,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+  DoNothing,
+  Schedule
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum State {
+  NotPressed,
+  Pressed,
+}
+
+/// Collects `SAMPLE_SIZE` readings of the button pin and votes on whether the
+/// button was really pressed.
+pub struct Debouncer {
+  sample_cnt: usize,
+  sample_data: [bool; SAMPLE_SIZE]
+}
+
+
+impl Message {
+  /// Variant name, for the trace
+  pub fn name(&self) -> &'static str {
+    match self {
+      Message::ButtonPressed => "ButtonPressed",
+      Message::ButtonNotPressed => "ButtonNotPressed",
+      Message::Subscribe => "Subscribe",
+      Message::Configure(_) => "Configure",
+      Message::Timer(..) => "Timer",
+      Message::Gesture(_) => "Gesture",
+    }
+  }
+}
+
+impl State {
+  pub fn next(self, msg: &Message) -> (State, Action) {
+    match (self, msg) {
+      (State::NotPressed, Message::ButtonPressed) => {
+        (State::Pressed, Action::Schedule)
+      }
+      (State::Pressed, Message::ButtonNotPressed) => {
+        (State::NotPressed, Action::DoNothing)
+      }
+      (s, _m) => {
+        (s, Action::DoNothing)
+      }
+    }
+  }
+}
+
+impl Debouncer {
+  pub const fn new() -> Self {
+    Debouncer {
+      sample_cnt: 0,
+      sample_data: [false; SAMPLE_SIZE]
+    }
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.sample_cnt >= SAMPLE_SIZE
+  }
+
+  /// Stores a pin reading, samples past the end of the window are dropped.
+  pub fn record(&mut self, level: bool) {
+    if !self.is_full() {
+      self.sample_data[self.sample_cnt] = level;
+      self.sample_cnt += 1;
+    }
+  }
+
+  /// Check to see if we have enough high samples to count as a button press
+  pub fn is_pressed(&self) -> bool {
+    let high_cnt = self.sample_data[..self.sample_cnt].iter()
+      .filter(|&x| *x)
+      .count();
+
+    high_cnt > SAMPLE_THRESHOLD
+  }
+
+  pub fn reset(&mut self) {
+    self.sample_cnt = 0;
+  }
+}
+
+impl Default for Debouncer {
+  fn default() -> Self {
+    Debouncer::new()
+  }
+}
+
+impl Timing {
+  pub const fn new() -> Self {
+    Timing {
+      long_press_ms: 800,
+      double_click_ms: 300,
+      repeat_ms: 200,
+    }
+  }
+}
+
+impl Default for Timing {
+  fn default() -> Self {
+    Timing::new()
+  }
+}
+
+impl Reaction {
+  const NONE: Reaction = Reaction { gesture: None, timer: None };
+
+  fn emit(gesture: Gesture) -> Self {
+    Reaction { gesture: Some(gesture), timer: None }
+  }
+
+  fn and_start(self, timer: Timer, ms: u32) -> Self {
+    Reaction { timer: Some((timer, ms)), ..self }
+  }
+}
+
+impl Recognizer {
+  pub const fn new() -> Self {
+    Recognizer {
+      timing: Timing::new(),
+      phase: Phase::Idle,
+      generation: 0,
+      repeats: 0,
+    }
+  }
+
+  /// Timers have to be scheduled with this
+  pub fn generation(&self) -> u16 {
+    self.generation
+  }
+
+  /// False once nothing is left to wait for
+  pub fn is_busy(&self) -> bool {
+    self.phase != Phase::Idle
+  }
+
+  pub fn press(&mut self) -> Reaction {
+    match self.phase {
+      Phase::Idle => {
+        self.enter(Phase::Down);
+        Reaction::NONE.and_start(Timer::LongPress, self.timing.long_press_ms)
+      }
+      Phase::Up => {
+        self.enter(Phase::SecondDown);
+        Reaction::emit(Gesture::DoubleClick)
+      }
+      _ => Reaction::NONE,
+    }
+  }
+
+  pub fn release(&mut self) -> Reaction {
+    match self.phase {
+      Phase::Down => {
+        self.enter(Phase::Up);
+        Reaction::emit(Gesture::Released).and_start(Timer::ClickWindow, self.timing.double_click_ms)
+      }
+      Phase::Held | Phase::SecondDown => {
+        self.enter(Phase::Idle);
+        Reaction::emit(Gesture::Released)
+      }
+      _ => Reaction::NONE,
+    }
+  }
+
+  pub fn timer(&mut self, timer: Timer, generation: u16) -> Reaction {
+    if generation != self.generation {
+      return Reaction::NONE;
+    }
+
+    match (self.phase, timer) {
+      (Phase::Down, Timer::LongPress) => {
+        self.enter(Phase::Held);
+        Reaction::emit(Gesture::LongPress).and_start(Timer::Repeat, self.timing.repeat_ms)
+      }
+      (Phase::Held, Timer::Repeat) => {
+        self.repeats = self.repeats.saturating_add(1);
+        Reaction::emit(Gesture::Repeat(self.repeats)).and_start(Timer::Repeat, self.timing.repeat_ms)
+      }
+      (Phase::Up, Timer::ClickWindow) => {
+        self.enter(Phase::Idle);
+        Reaction::emit(Gesture::ShortPress)
+      }
+      _ => Reaction::NONE,
+    }
+  }
+
+  // timers of the phase left behind go stale
+  fn enter(&mut self, phase: Phase) {
+    self.phase = phase;
+    self.generation = self.generation.wrapping_add(1);
+    self.repeats = 0;
+  }
+}
+
+impl Default for Recognizer {
+  fn default() -> Self {
+    Recognizer::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fill(samples: &[bool]) -> Debouncer {
+    let mut debouncer = Debouncer::new();
+    for &s in samples {
+      debouncer.record(s);
+    }
+    debouncer
+  }
+
+  #[test]
+  fn press_starts_sampling_and_release_rearms() {
+    assert_eq!(State::NotPressed.next(&Message::ButtonPressed), (State::Pressed, Action::Schedule));
+    assert_eq!(State::Pressed.next(&Message::ButtonPressed), (State::Pressed, Action::DoNothing));
+    assert_eq!(State::Pressed.next(&Message::ButtonNotPressed), (State::NotPressed, Action::DoNothing));
+  }
+
+  #[test]
+  fn window_fills_after_sample_size_readings() {
+    let mut debouncer = Debouncer::new();
+    for _ in 0..SAMPLE_SIZE - 1 {
+      debouncer.record(true);
+      assert!(!debouncer.is_full());
+    }
+    debouncer.record(true);
+    assert!(debouncer.is_full());
+  }
+
+  #[test]
+  fn majority_of_high_samples_is_a_press() {
+    let mut samples = [false; SAMPLE_SIZE];
+    for s in samples.iter_mut().take(SAMPLE_THRESHOLD + 1) {
+      *s = true;
+    }
+    assert!(fill(&samples).is_pressed());
+  }
+
+  #[test]
+  fn threshold_or_fewer_high_samples_is_bounce() {
+    let mut samples = [false; SAMPLE_SIZE];
+    for s in samples.iter_mut().take(SAMPLE_THRESHOLD) {
+      *s = true;
+    }
+    assert!(!fill(&samples).is_pressed());
+  }
+
+  // feeds the recognizer and fires the timers it asks for whenever `fire` has
+  // them, the gestures come out in order
+  fn gestures(recognizer: &mut Recognizer, inputs: &[&str]) -> Vec<Gesture> {
+    let mut out = Vec::new();
+    let mut pending = None;
+
+    for input in inputs {
+      let reaction = match *input {
+        "down" => recognizer.press(),
+        "up" => recognizer.release(),
+        "fire" => {
+          let (timer, _) = pending.take().expect("no timer pending");
+          recognizer.timer(timer, recognizer.generation())
+        }
+        other => panic!("unknown input {}", other),
+      };
+
+      out.extend(reaction.gesture);
+      if reaction.timer.is_some() {
+        pending = reaction.timer;
+      }
+    }
+    out
+  }
+
+  #[test]
+  fn short_press_comes_once_the_click_window_closed() {
+    let mut recognizer = Recognizer::new();
+
+    assert_eq!(recognizer.press(), Reaction { gesture: None, timer: Some((Timer::LongPress, 800)) });
+    assert_eq!(recognizer.release(), Reaction { gesture: Some(Gesture::Released), timer: Some((Timer::ClickWindow, 300)) });
+    assert!(recognizer.is_busy());
+
+    let window = recognizer.timer(Timer::ClickWindow, recognizer.generation());
+    assert_eq!(window.gesture, Some(Gesture::ShortPress));
+    assert!(!recognizer.is_busy());
+  }
+
+  #[test]
+  fn second_press_within_the_window_is_a_double_click() {
+    let mut recognizer = Recognizer::new();
+
+    let out = gestures(&mut recognizer, &["down", "up", "down", "up"]);
+    assert_eq!(out, [Gesture::Released, Gesture::DoubleClick, Gesture::Released]);
+    assert!(!recognizer.is_busy());
+  }
+
+  #[test]
+  fn holding_repeats_until_released() {
+    let mut recognizer = Recognizer::new();
+
+    let out = gestures(&mut recognizer, &["down", "fire", "fire", "fire", "up"]);
+    assert_eq!(out, [Gesture::LongPress, Gesture::Repeat(1), Gesture::Repeat(2), Gesture::Released]);
+  }
+
+  #[test]
+  fn stale_timers_are_ignored() {
+    let mut recognizer = Recognizer::new();
+    let long_press = recognizer.generation().wrapping_add(1);
+    recognizer.press();
+    recognizer.release();
+
+    // the long press timer of the first press fires after its release
+    assert_eq!(recognizer.timer(Timer::LongPress, long_press), Reaction::NONE);
+    assert_eq!(recognizer.timer(Timer::ClickWindow, long_press), Reaction::NONE);
+  }
+
+  #[test]
+  fn thresholds_can_be_changed() {
+    let mut recognizer = Recognizer::new();
+    recognizer.timing = Timing { long_press_ms: 2_000, ..Timing::new() };
+
+    assert_eq!(recognizer.press().timer, Some((Timer::LongPress, 2_000)));
+  }
+
+  #[test]
+  fn reset_discards_old_samples() {
+    let mut debouncer = fill(&[true; SAMPLE_SIZE]);
+    debouncer.reset();
+    assert!(!debouncer.is_full());
+    assert!(!debouncer.is_pressed());
+  }
+}
@@ -0,0 +1,228 @@
+//! Health of the firmware as the heartbeat shows it. Tasks report their fault
+//! counts, the monitor looks at how many came in lately and picks the blink
+//! code for the worst of them.
+
+use crate::heartbeat::Breathing;
+use crate::pattern::{Pattern, Level, on, off};
+
+/// A task's fault count since start-up
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+  /// Bus faults of the SPI driver, timeouts included
+  SpiErrors(u32),
+  /// Accelerometer transfers that never completed
+  LisTimeouts(u32),
+  /// Messages lost by mailboxes that retry rather than drop
+  MailboxDrops(u32),
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Clone, Copy)]
+pub enum Health {
+  Ok,
+  Degraded,
+  Fault,
+}
+
+/// What the heartbeat LED shows
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Indication {
+  Breathe,
+  Blink(&'static Pattern),
+}
+
+/// Two short blinks every two seconds
+pub const DEGRADED: Pattern = Pattern::forever(&[on(100), off(150), on(100), off(1_650)]);
+/// Five flashes a second
+pub const FAULT: Pattern = Pattern::forever(&[on(100), off(100)]);
+
+/// Faults count for the window they came in and the one after, so even a
+/// single one shows for a whole window
+pub const WINDOW_MS: u32 = 10_000;
+
+const SOURCE_COUNT: usize = 3;
+
+// faults in a window that make a fault rather than a degraded state
+const FAULT_AT: [u32; SOURCE_COUNT] = [10, 3, 10];
+
+pub struct Monitor {
+  latest: [u32; SOURCE_COUNT],
+  // counts when the current window opened
+  opened: [u32; SOURCE_COUNT],
+  // faults in the window before
+  previous: [u32; SOURCE_COUNT],
+  window_ms: u32,
+}
+
+
+impl Status {
+  fn source(self) -> (usize, u32) {
+    match self {
+      Status::SpiErrors(count) => (0, count),
+      Status::LisTimeouts(count) => (1, count),
+      Status::MailboxDrops(count) => (2, count),
+    }
+  }
+}
+
+impl Health {
+  pub fn indication(self) -> Indication {
+    match self {
+      Health::Ok => Indication::Breathe,
+      Health::Degraded => Indication::Blink(&DEGRADED),
+      Health::Fault => Indication::Blink(&FAULT),
+    }
+  }
+}
+
+impl Indication {
+  /// `None` once a pattern has played out. The breath changes all the time,
+  /// it holds for 0ms. Blink codes ignore the peak of the breath.
+  pub fn at(self, elapsed_ms: u32, breathing: &Breathing) -> Option<Level> {
+    match self {
+      Indication::Breathe => Some(Level { brightness: breathing.brightness(elapsed_ms), remaining_ms: 0 }),
+      Indication::Blink(pattern) => pattern.at(elapsed_ms),
+    }
+  }
+
+  /// Keeps the time into whatever repeats forever from growing without bound
+  pub fn wrap(self, elapsed_ms: u32, breathing: &Breathing) -> u32 {
+    match self {
+      Indication::Breathe => elapsed_ms % breathing.period_ms.max(1),
+      Indication::Blink(pattern) => pattern.wrap(elapsed_ms),
+    }
+  }
+}
+
+impl Monitor {
+  pub const fn new() -> Self {
+    Monitor {
+      latest: [0; SOURCE_COUNT],
+      opened: [0; SOURCE_COUNT],
+      previous: [0; SOURCE_COUNT],
+      window_ms: 0,
+    }
+  }
+
+  pub fn report(&mut self, status: Status) {
+    let (source, count) = status.source();
+    self.latest[source] = count;
+  }
+
+  /// Moves time on by `ms`, a window closes every `WINDOW_MS`
+  pub fn tick(&mut self, ms: u32) {
+    self.window_ms = self.window_ms.saturating_add(ms);
+    if self.window_ms < WINDOW_MS {
+      return;
+    }
+
+    self.window_ms = 0;
+    for source in 0..SOURCE_COUNT {
+      self.previous[source] = self.latest[source].wrapping_sub(self.opened[source]);
+      self.opened[source] = self.latest[source];
+    }
+  }
+
+  /// The worst of the sources
+  pub fn health(&self) -> Health {
+    (0..SOURCE_COUNT).map(|source| {
+      let current = self.latest[source].wrapping_sub(self.opened[source]);
+      match current.max(self.previous[source]) {
+        0 => Health::Ok,
+        faults if faults < FAULT_AT[source] => Health::Degraded,
+        _ => Health::Fault,
+      }
+    }).max().unwrap_or(Health::Ok)
+  }
+
+  pub fn indication(&self) -> Indication {
+    self.health().indication()
+  }
+}
+
+impl Default for Monitor {
+  fn default() -> Self {
+    Monitor::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::leds::FULL;
+
+  #[test]
+  fn no_faults_breathe() {
+    let mut monitor = Monitor::new();
+    monitor.report(Status::SpiErrors(0));
+
+    assert_eq!(monitor.health(), Health::Ok);
+    assert_eq!(monitor.indication(), Indication::Breathe);
+  }
+
+  #[test]
+  fn a_few_faults_degrade() {
+    let mut monitor = Monitor::new();
+    monitor.report(Status::SpiErrors(2));
+
+    assert_eq!(monitor.indication(), Indication::Blink(&DEGRADED));
+  }
+
+  #[test]
+  fn sources_have_their_own_thresholds() {
+    let mut monitor = Monitor::new();
+    monitor.report(Status::SpiErrors(3));
+    assert_eq!(monitor.health(), Health::Degraded);
+
+    monitor.report(Status::LisTimeouts(3));
+    assert_eq!(monitor.health(), Health::Fault);
+    assert_eq!(monitor.indication(), Indication::Blink(&FAULT));
+  }
+
+  #[test]
+  fn the_worst_source_wins() {
+    let mut monitor = Monitor::new();
+    monitor.report(Status::MailboxDrops(10));
+    monitor.report(Status::SpiErrors(1));
+
+    assert_eq!(monitor.health(), Health::Fault);
+  }
+
+  #[test]
+  fn faults_age_out_after_two_windows() {
+    let mut monitor = Monitor::new();
+    monitor.report(Status::LisTimeouts(1));
+
+    monitor.tick(WINDOW_MS);
+    assert_eq!(monitor.health(), Health::Degraded);
+
+    // the count stayed where it was for a whole window
+    monitor.tick(WINDOW_MS);
+    assert_eq!(monitor.health(), Health::Ok);
+  }
+
+  #[test]
+  fn only_new_faults_count() {
+    let mut monitor = Monitor::new();
+    monitor.report(Status::SpiErrors(50));
+    monitor.tick(WINDOW_MS);
+    monitor.tick(WINDOW_MS);
+
+    monitor.report(Status::SpiErrors(52));
+    assert_eq!(monitor.health(), Health::Degraded);
+  }
+
+  #[test]
+  fn blink_codes_ignore_the_peak() {
+    let breathing = Breathing { peak: 100, ..Breathing::new() };
+
+    let brightness = |indication: Indication, t| indication.at(t, &breathing).unwrap().brightness;
+
+    let lit: Vec<u32> = (0..2_000).step_by(50).filter(|t| brightness(Indication::Blink(&DEGRADED), *t) == FULL).collect();
+    assert_eq!(lit, [0, 50, 250, 300]);
+
+    assert_eq!(brightness(Indication::Blink(&FAULT), 150), 0);
+    assert_eq!(brightness(Indication::Blink(&FAULT), 1_050), FULL);
+    assert_eq!(brightness(Indication::Breathe, 1_260), 100);
+  }
+}
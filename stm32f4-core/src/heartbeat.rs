@@ -0,0 +1,267 @@
+//! Breathing of the heartbeat LED. Brightness is worked out from the time into
+//! the breath, in thousandths of `leds::FULL`, so it neither depends on how
+//! often it is updated nor on the PWM frequency.
+
+use crate::leds::FULL;
+use crate::health::Status;
+use crate::pattern::Pattern;
+
+#[derive(Debug)]
+pub enum Message {
+  TurnOff,
+  TurnOn,
+  Toggle,
+  SetWaveform(Waveform),
+  /// Length of one breath, in and out
+  SetPeriod { period_ms: u32 },
+  /// Brightness at the top of a breath
+  SetPeak(u16),
+  /// From the tasks the heartbeat keeps an eye on, see `health`
+  ReportStatus(Status),
+  /// Shown instead of the breath until it has played out or the next one
+  /// comes, a blink code for the health goes first
+  Play(&'static Pattern),
+}
+
+/// Shape of a breath
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Waveform {
+  /// Raised cosine, eases in and out at both ends
+  Sine,
+  /// Straight up and down
+  Triangle,
+  /// Straight up and down after a gamma of 2.2, the eye sees even steps
+  Exponential,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum State {
+  Off,
+  On,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+  DoNothing,
+  Schedule
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Breathing {
+  pub waveform: Waveform,
+  pub period_ms: u32,
+  pub peak: u16,
+}
+
+// (i / 32)^2.2 in thousandths, interpolated in between
+const GAMMA: [u16; 33] = [
+  0, 0, 2, 5, 10, 17, 25, 35, 47, 61, 77, 95, 116, 138, 162, 189, 218,
+  249, 282, 318, 356, 396, 439, 484, 531, 581, 633, 688, 745, 805, 868, 933, 1000,
+];
+
+
+impl Message {
+  /// Variant name, for the trace
+  pub fn name(&self) -> &'static str {
+    match self {
+      Message::TurnOff => "TurnOff",
+      Message::TurnOn => "TurnOn",
+      Message::Toggle => "Toggle",
+      Message::SetWaveform(_) => "SetWaveform",
+      Message::SetPeriod { .. } => "SetPeriod",
+      Message::SetPeak(_) => "SetPeak",
+      Message::ReportStatus(_) => "ReportStatus",
+      Message::Play(_) => "Play",
+    }
+  }
+}
+
+impl State {
+  pub fn next(self, msg: &Message) -> (State, Action) {
+    match (self, msg) {
+      (State::Off, Message::TurnOn) => {
+        (State::On, Action::Schedule)
+      }
+      (State::Off, Message::Toggle) => {
+        (State::On, Action::Schedule)
+      }
+      (State::Off, Message::Play(_)) => {
+        (State::On, Action::Schedule)
+      }
+      (State::On, Message::TurnOff) => {
+        (State::Off, Action::DoNothing)
+      }
+      (State::On, Message::Toggle) => {
+        (State::Off, Action::DoNothing)
+      }
+      (s, _m) => {
+        (s, Action::DoNothing)
+      }
+    }
+  }
+}
+
+impl Breathing {
+  pub const fn new() -> Self {
+    Breathing {
+      waveform: Waveform::Sine,
+      period_ms: 2_520,
+      peak: FULL,
+    }
+  }
+
+  /// Takes the settings out of the message, ignores the rest
+  pub fn apply(&mut self, msg: &Message) {
+    match *msg {
+      Message::SetWaveform(waveform) => self.waveform = waveform,
+      Message::SetPeriod { period_ms } => self.period_ms = period_ms.max(1),
+      Message::SetPeak(peak) => self.peak = peak.min(FULL),
+      _ => {}
+    }
+  }
+
+  /// `elapsed_ms` since the breathing started, it repeats every period
+  pub fn brightness(&self, elapsed_ms: u32) -> u16 {
+    let period = self.period_ms.max(1) as u64;
+    // thousandths of the way through the breath
+    let phase = ((elapsed_ms as u64 % period) * FULL as u64 / period) as u32;
+    let level = match self.waveform {
+      Waveform::Sine => {
+        let s = half_sine(phase);
+        s * s / FULL as u32
+      }
+      Waveform::Triangle => triangle(phase),
+      Waveform::Exponential => gamma(triangle(phase)),
+    };
+
+    (level * self.peak as u32 / FULL as u32) as u16
+  }
+}
+
+impl Default for Breathing {
+  fn default() -> Self {
+    Breathing::new()
+  }
+}
+
+// up to FULL halfway through, back to 0 at the end
+fn triangle(phase: u32) -> u32 {
+  let full = FULL as u32;
+  if phase < full / 2 {
+    phase * 2
+  } else {
+    (full - phase) * 2
+  }
+}
+
+// sin(pi * phase) after Bhaskara, off by 2 thousandths at most
+fn half_sine(phase: u32) -> u32 {
+  let full = FULL as u64;
+  let p = phase as u64 * (full - phase as u64);
+  (16 * p * full / (5 * full * full - 4 * p)) as u32
+}
+
+fn gamma(level: u32) -> u32 {
+  let steps = (GAMMA.len() - 1) as u32;
+  let scaled = level * steps;
+  let i = (scaled / FULL as u32) as usize;
+  if i >= GAMMA.len() - 1 {
+    return FULL as u32;
+  }
+
+  let (low, high) = (GAMMA[i] as u32, GAMMA[i + 1] as u32);
+  low + (high - low) * (scaled % FULL as u32) / FULL as u32
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn turning_on_schedules_the_breathing_task() {
+    assert_eq!(State::Off.next(&Message::TurnOn), (State::On, Action::Schedule));
+    assert_eq!(State::Off.next(&Message::Toggle), (State::On, Action::Schedule));
+  }
+
+  #[test]
+  fn turning_off_lets_the_breathing_task_expire() {
+    assert_eq!(State::On.next(&Message::TurnOff), (State::Off, Action::DoNothing));
+    assert_eq!(State::On.next(&Message::Toggle), (State::Off, Action::DoNothing));
+  }
+
+  #[test]
+  fn patterns_turn_the_heartbeat_on() {
+    const BLINK: Pattern = Pattern::times(1, &[crate::pattern::on(100)]);
+
+    assert_eq!(State::Off.next(&Message::Play(&BLINK)), (State::On, Action::Schedule));
+    assert_eq!(State::On.next(&Message::Play(&BLINK)), (State::On, Action::DoNothing));
+  }
+
+  #[test]
+  fn settings_leave_the_state_alone() {
+    assert_eq!(State::On.next(&Message::SetPeak(10)), (State::On, Action::DoNothing));
+    assert_eq!(State::Off.next(&Message::ReportStatus(Status::SpiErrors(1))), (State::Off, Action::DoNothing));
+    assert_eq!(State::Off.next(&Message::SetPeriod { period_ms: 100 }), (State::Off, Action::DoNothing));
+  }
+
+  #[test]
+  fn every_waveform_breathes_from_dark_to_peak() {
+    for waveform in [Waveform::Sine, Waveform::Triangle, Waveform::Exponential].iter() {
+      let breathing = Breathing { waveform: *waveform, period_ms: 2_000, peak: FULL };
+
+      assert_eq!(breathing.brightness(0), 0, "{:?}", waveform);
+      assert_eq!(breathing.brightness(1_000), FULL, "{:?}", waveform);
+      assert_eq!(breathing.brightness(2_000), 0, "{:?}", waveform);
+      // it goes out the way it came in
+      assert_eq!(breathing.brightness(400), breathing.brightness(1_600), "{:?}", waveform);
+    }
+  }
+
+  #[test]
+  fn waveforms_differ_in_shape() {
+    let at_quarter = |waveform| Breathing { waveform, period_ms: 2_000, peak: FULL }.brightness(500);
+
+    assert_eq!(at_quarter(Waveform::Triangle), 500);
+    assert!((at_quarter(Waveform::Sine) as i32 - 500).abs() <= 5);
+    assert_eq!(at_quarter(Waveform::Exponential), 218);
+  }
+
+  #[test]
+  fn brightness_rises_steadily() {
+    for waveform in [Waveform::Sine, Waveform::Triangle, Waveform::Exponential].iter() {
+      let breathing = Breathing { waveform: *waveform, period_ms: 2_000, peak: FULL };
+      let levels: Vec<u16> = (0..=1_000).step_by(10).map(|t| breathing.brightness(t)).collect();
+
+      assert!(levels.windows(2).all(|w| w[0] <= w[1]), "{:?}", waveform);
+    }
+  }
+
+  #[test]
+  fn peak_scales_the_breath() {
+    let mut breathing = Breathing::new();
+    breathing.apply(&Message::SetPeak(400));
+    breathing.apply(&Message::SetPeriod { period_ms: 1_000 });
+
+    assert_eq!(breathing.brightness(500), 400);
+    assert_eq!(breathing.brightness(1_500), 400);
+
+    breathing.apply(&Message::SetPeak(u16::MAX));
+    assert_eq!(breathing.peak, FULL);
+  }
+
+  #[test]
+  fn zero_periods_stay_dark() {
+    let mut breathing = Breathing::new();
+    breathing.apply(&Message::SetPeriod { period_ms: 0 });
+
+    assert_eq!(breathing.period_ms, 1);
+    assert_eq!(breathing.brightness(12_345), 0);
+  }
+
+  #[test]
+  fn repeated_commands_are_ignored() {
+    assert_eq!(State::On.next(&Message::TurnOn), (State::On, Action::DoNothing));
+    assert_eq!(State::Off.next(&Message::TurnOff), (State::Off, Action::DoNothing));
+  }
+}
@@ -0,0 +1,290 @@
+//! The four user LEDs of the Discovery board, each running an effect of its
+//! own. Effects are worked out from the time since they started, the firmware
+//! advances that time in ticks and puts the brightness on the PWM channels.
+
+use crate::pattern::Pattern;
+
+/// Brightness is given in thousandths of this
+pub const FULL: u16 = 1000;
+
+pub const LED_COUNT: usize = 4;
+
+/// In the order of the TIM4 channels driving them
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Led {
+  Green,
+  Orange,
+  Red,
+  Blue,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Message {
+  /// Holds the LED at `brightness`
+  SetBrightness { led: Led, brightness: u16 },
+  /// On at `brightness` for the first half of every period, off for the second
+  Blink { led: Led, brightness: u16, period_ms: u32 },
+  /// Ramps from where the LED is to `to` in `over_ms`, then holds it there
+  Fade { led: Led, to: u16, over_ms: u32 },
+  /// Plays a blink code, the LED goes dark once it has played out
+  Pattern { led: Led, pattern: &'static Pattern },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Effect {
+  Steady(u16),
+  Blink { brightness: u16, period_ms: u32 },
+  Fade { from: u16, to: u16, over_ms: u32 },
+  Pattern(&'static Pattern),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+  DoNothing,
+  // the tick task has to be started
+  Schedule,
+}
+
+pub struct Leds {
+  effects: [Effect; LED_COUNT],
+  // ms since each effect started
+  elapsed: [u32; LED_COUNT],
+  ticking: bool,
+}
+
+
+impl Led {
+  pub const ALL: [Led; LED_COUNT] = [Led::Green, Led::Orange, Led::Red, Led::Blue];
+
+  pub fn index(self) -> usize {
+    self as usize
+  }
+}
+
+impl Message {
+  pub fn led(&self) -> Led {
+    match *self {
+      Message::SetBrightness { led, .. } => led,
+      Message::Blink { led, .. } => led,
+      Message::Fade { led, .. } => led,
+      Message::Pattern { led, .. } => led,
+    }
+  }
+
+  /// Variant name, what the trace keeps of the message
+  pub fn name(&self) -> &'static str {
+    match self {
+      Message::SetBrightness { .. } => "SetBrightness",
+      Message::Blink { .. } => "Blink",
+      Message::Fade { .. } => "Fade",
+      Message::Pattern { .. } => "Pattern",
+    }
+  }
+}
+
+impl Effect {
+  pub fn brightness(self, elapsed_ms: u32) -> u16 {
+    match self {
+      Effect::Steady(brightness) => brightness,
+      Effect::Blink { brightness, period_ms } => {
+        if period_ms == 0 || elapsed_ms % period_ms < period_ms / 2 {
+          brightness
+        } else {
+          0
+        }
+      }
+      Effect::Fade { from, to, over_ms } => {
+        if elapsed_ms >= over_ms {
+          return to;
+        }
+        let from = from as i32;
+        let delta = (to as i32 - from) as i64 * elapsed_ms as i64 / over_ms as i64;
+        (from + delta as i32) as u16
+      }
+      Effect::Pattern(pattern) => pattern.at(elapsed_ms).map_or(0, |level| level.brightness.min(FULL)),
+    }
+  }
+
+  /// Whether it needs ticks to change
+  pub fn is_animated(self) -> bool {
+    !matches!(self, Effect::Steady(_))
+  }
+
+  // a finished fade holds where it ended, a finished pattern goes dark
+  fn settle(self, elapsed_ms: u32) -> Effect {
+    match self {
+      Effect::Fade { to, over_ms, .. } if elapsed_ms >= over_ms => Effect::Steady(to),
+      Effect::Pattern(pattern) if pattern.at(elapsed_ms).is_none() => Effect::Steady(0),
+      e => e,
+    }
+  }
+}
+
+impl Leds {
+  pub const fn new() -> Self {
+    Leds {
+      effects: [Effect::Steady(0); LED_COUNT],
+      elapsed: [0; LED_COUNT],
+      ticking: false,
+    }
+  }
+
+  /// Replaces the effect of the LED the message is for
+  pub fn apply(&mut self, msg: &Message) -> Action {
+    let led = msg.led();
+    let effect = match *msg {
+      Message::SetBrightness { brightness, .. } => Effect::Steady(brightness.min(FULL)),
+      Message::Blink { brightness, period_ms, .. } => Effect::Blink { brightness: brightness.min(FULL), period_ms },
+      Message::Fade { to, over_ms, .. } => Effect::Fade { from: self.brightness(led), to: to.min(FULL), over_ms },
+      Message::Pattern { pattern, .. } => Effect::Pattern(pattern),
+    };
+
+    self.effects[led.index()] = effect.settle(0);
+    self.elapsed[led.index()] = 0;
+
+    if self.is_animated(led) && !self.ticking {
+      self.ticking = true;
+      Action::Schedule
+    } else {
+      Action::DoNothing
+    }
+  }
+
+  pub fn brightness(&self, led: Led) -> u16 {
+    self.effects[led.index()].brightness(self.elapsed[led.index()])
+  }
+
+  pub fn is_animated(&self, led: Led) -> bool {
+    self.effects[led.index()].is_animated()
+  }
+
+  /// Advances every effect by `ms`. Gives false once none of them changes any
+  /// more, the tick task stops then.
+  pub fn tick(&mut self, ms: u32) -> bool {
+    for (effect, elapsed) in self.effects.iter_mut().zip(self.elapsed.iter_mut()) {
+      if effect.is_animated() {
+        *elapsed = elapsed.wrapping_add(ms);
+        if let Effect::Pattern(pattern) = effect {
+          *elapsed = pattern.wrap(*elapsed);
+        }
+        *effect = effect.settle(*elapsed);
+      }
+    }
+
+    self.ticking = self.effects.iter().any(|e| e.is_animated());
+    self.ticking
+  }
+}
+
+impl Default for Leds {
+  fn default() -> Self {
+    Leds::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pattern::{on, off};
+
+  #[test]
+  fn steady_brightness_needs_no_ticks() {
+    let mut leds = Leds::new();
+
+    let action = leds.apply(&Message::SetBrightness { led: Led::Red, brightness: 400 });
+    assert_eq!(action, Action::DoNothing);
+    assert_eq!(leds.brightness(Led::Red), 400);
+    assert_eq!(leds.brightness(Led::Green), 0);
+
+    leds.apply(&Message::SetBrightness { led: Led::Red, brightness: 5_000 });
+    assert_eq!(leds.brightness(Led::Red), FULL);
+  }
+
+  #[test]
+  fn only_the_first_animation_starts_the_ticks() {
+    let mut leds = Leds::new();
+
+    let blink = Message::Blink { led: Led::Blue, brightness: FULL, period_ms: 100 };
+    assert_eq!(leds.apply(&blink), Action::Schedule);
+    let blink = Message::Blink { led: Led::Green, brightness: FULL, period_ms: 100 };
+    assert_eq!(leds.apply(&blink), Action::DoNothing);
+  }
+
+  #[test]
+  fn blinks_are_on_for_half_the_period() {
+    let mut leds = Leds::new();
+    leds.apply(&Message::Blink { led: Led::Blue, brightness: 800, period_ms: 100 });
+
+    assert_eq!(leds.brightness(Led::Blue), 800);
+    leds.tick(40);
+    assert_eq!(leds.brightness(Led::Blue), 800);
+    leds.tick(20);
+    assert_eq!(leds.brightness(Led::Blue), 0);
+    leds.tick(40);
+    assert_eq!(leds.brightness(Led::Blue), 800);
+  }
+
+  #[test]
+  fn fades_start_where_the_led_is_and_hold_at_the_end() {
+    let mut leds = Leds::new();
+    leds.apply(&Message::SetBrightness { led: Led::Orange, brightness: 1000 });
+    leds.apply(&Message::Fade { led: Led::Orange, to: 200, over_ms: 400 });
+
+    assert!(leds.tick(100));
+    assert_eq!(leds.brightness(Led::Orange), 800);
+    assert!(leds.tick(200));
+    assert_eq!(leds.brightness(Led::Orange), 400);
+
+    // the ticks stop once the fade is done
+    assert!(!leds.tick(100));
+    assert_eq!(leds.brightness(Led::Orange), 200);
+    assert!(!leds.is_animated(Led::Orange));
+  }
+
+  #[test]
+  fn instant_fades_jump() {
+    let mut leds = Leds::new();
+    let action = leds.apply(&Message::Fade { led: Led::Green, to: 600, over_ms: 0 });
+
+    assert_eq!(action, Action::DoNothing);
+    assert_eq!(leds.brightness(Led::Green), 600);
+  }
+
+  #[test]
+  fn patterns_play_out_and_go_dark() {
+    const TWICE: Pattern = Pattern::times(2, &[on(10), off(10)]);
+    let mut leds = Leds::new();
+    assert_eq!(leds.apply(&Message::Pattern { led: Led::Red, pattern: &TWICE }), Action::Schedule);
+
+    let mut timeline = Vec::new();
+    while leds.is_animated(Led::Red) {
+      timeline.push(leds.brightness(Led::Red) == FULL);
+      leds.tick(10);
+    }
+    assert_eq!(timeline, [true, false, true, false]);
+    assert_eq!(leds.brightness(Led::Red), 0);
+  }
+
+  #[test]
+  fn endless_patterns_keep_ticking() {
+    const BLINK: Pattern = Pattern::forever(&[on(10), off(10)]);
+    let mut leds = Leds::new();
+    leds.apply(&Message::Pattern { led: Led::Blue, pattern: &BLINK });
+
+    for _ in 0..1_000 {
+      assert!(leds.tick(10));
+    }
+    assert_eq!(leds.brightness(Led::Blue), FULL);
+  }
+
+  #[test]
+  fn empty_patterns_stay_dark() {
+    const EMPTY: Pattern = Pattern::forever(&[]);
+    let mut leds = Leds::new();
+    let action = leds.apply(&Message::Pattern { led: Led::Green, pattern: &EMPTY });
+
+    assert_eq!(action, Action::DoNothing);
+    assert_eq!(leds.brightness(Led::Green), 0);
+  }
+}
@@ -0,0 +1,12 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod heartbeat;
+pub mod leds;
+pub mod health;
+pub mod pattern;
+pub mod button;
+pub mod spi_drv;
+pub mod lis3dsh;
+pub mod trace;
+pub mod mailbox;
+pub mod time;
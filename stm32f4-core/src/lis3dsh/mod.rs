@@ -0,0 +1,531 @@
+pub mod registers;
+#[cfg(test)]
+pub mod sim;
+
+use crate::spi_drv;
+use registers::{
+  Register,
+  CtrlReg4,
+  CtrlReg5,
+  OutX,
+  WhoAmI,
+};
+
+pub use registers::{
+  DataRate,
+  Scale,
+  Bandwidth,
+  SelfTest,
+};
+
+#[derive(Debug)]
+pub enum Message {
+  ReadID,
+  ReadAxes,
+  ChangeScale(Scale),
+  ChangeBandwidth(Bandwidth),
+  ChangeSelfTest(SelfTest),
+  ChangeDataRate(DataRate),
+  ChangeBDU(bool),
+  ReadComplete,
+  WriteComplete,
+  CommandRejected,
+  // the bus gave up on the transfer, e.g. because it timed out
+  TransferFailed(spi_drv::Error),
+  // a period of 0 streams at the configured data rate
+  StartStreaming { period_us: u32 },
+  StopStreaming,
+  // carries the generation of the stream that scheduled it
+  StreamTick(u32),
+  // carries the id of the transaction it guards
+  Watchdog(spi_drv::TransactionId),
+}
+
+/// Copy of what has been written to the control registers. Changing a single
+/// setting rewrites the whole register from here so the others are preserved.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Configuration {
+  pub ctrl_reg4: CtrlReg4,
+  pub ctrl_reg5: CtrlReg5,
+}
+
+/// Bookkeeping for periodic sampling of the axes
+#[derive(Debug, Default)]
+pub struct Stream {
+  pub active: bool,
+  pub period_us: u32,
+  pub generation: u32,
+  // ticks that arrived while a transfer was still in progress
+  pub dropped: u32,
+  // ticks that ran more than a full period after they were due
+  pub late: u32,
+}
+
+#[derive(Debug)]
+pub enum Action {
+  DoNothing,
+  StartRead(spi_drv::Read),
+  StartWrite(spi_drv::Write),
+  HandleData,
+  HandleError,
+  StartStream(u32),
+  StopStream,
+  StreamRead(spi_drv::Read),
+  DropSample,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum State {
+  Idling,
+  Busy,
+}
+
+
+pub fn calculate_1g(input: i16, scale: &Scale) -> f32 {
+  let scale: u8 = match scale {
+    Scale::TwoG => 2,
+    Scale::FourG => 4,
+    Scale::SixG => 6,
+    Scale::EightG => 8,
+    Scale::SixteenG => 16,
+  };
+
+  // division is by 2^15 because value is signed 16-bit
+  (f32::from(input) * f32::from(scale)) / 32768.0
+}
+
+
+impl DataRate {
+  /// Time between two output data updates, `None` when the device is powered down
+  pub fn period_us(&self) -> Option<u32> {
+    match self {
+      DataRate::Zero => None,
+      DataRate::ThreePointOneTwoFiveHertz => Some(320_000),
+      DataRate::SixPointTwoFiveHertz => Some(160_000),
+      DataRate::TwelvePointFiveHertz => Some(80_000),
+      DataRate::TwentyFiveHertz => Some(40_000),
+      DataRate::FiftyHertz => Some(20_000),
+      DataRate::OneHundredHertz => Some(10_000),
+      DataRate::FourHundredHertz => Some(2_500),
+      DataRate::EightHundredHertz => Some(1_250),
+      DataRate::SixteenHundredHertz => Some(625),
+    }
+  }
+}
+
+impl Configuration {
+  /// The configuration once the change requested by `msg` has been written
+  pub fn with(&self, msg: &Message) -> Configuration {
+    let mut config = *self;
+
+    match msg {
+      Message::ChangeScale(scale) => config.ctrl_reg5.scale = *scale,
+      Message::ChangeBandwidth(bandwidth) => config.ctrl_reg5.bandwidth = *bandwidth,
+      Message::ChangeSelfTest(self_test) => config.ctrl_reg5.self_test = *self_test,
+      Message::ChangeDataRate(rate) => config.ctrl_reg4.data_rate = *rate,
+      Message::ChangeBDU(bdu) => config.ctrl_reg4.bdu = *bdu,
+      _ => (),
+    }
+
+    config
+  }
+}
+
+/// Maps the outcome of a bus transaction onto the driver's own messages
+impl From<&spi_drv::Completion> for Message {
+  fn from(completion: &spi_drv::Completion) -> Message {
+    match completion.result {
+      Ok(spi_drv::Response::Read(_)) => Message::ReadComplete,
+      Ok(spi_drv::Response::Written) => Message::WriteComplete,
+      Err(spi_drv::Error::Rejected) => Message::CommandRejected,
+      Err(e) => Message::TransferFailed(e),
+    }
+  }
+}
+
+impl Message {
+  /// Outcomes of a bus transaction. They are only taken from the driver's
+  /// completions, which carry the data read.
+  pub fn is_completion(&self) -> bool {
+    matches!(self, Message::ReadComplete | Message::WriteComplete | Message::CommandRejected | Message::TransferFailed(_))
+  }
+
+  /// Variant name, for the trace
+  pub fn name(&self) -> &'static str {
+    match self {
+      Message::ReadID => "ReadID",
+      Message::ReadAxes => "ReadAxes",
+      Message::ChangeScale(_) => "ChangeScale",
+      Message::ChangeBandwidth(_) => "ChangeBandwidth",
+      Message::ChangeSelfTest(_) => "ChangeSelfTest",
+      Message::ChangeDataRate(_) => "ChangeDataRate",
+      Message::ChangeBDU(_) => "ChangeBDU",
+      Message::ReadComplete => "ReadComplete",
+      Message::WriteComplete => "WriteComplete",
+      Message::CommandRejected => "CommandRejected",
+      Message::TransferFailed(_) => "TransferFailed",
+      Message::StartStreaming { .. } => "StartStreaming",
+      Message::StopStreaming => "StopStreaming",
+      Message::StreamTick(_) => "StreamTick",
+      Message::Watchdog(_) => "Watchdog",
+    }
+  }
+}
+
+// auto-increment is enabled by default so this will read out all the axis registers
+fn read_axes() -> spi_drv::Read {
+  registers::read_burst::<OutX>(3 * OutX::SIZE)
+}
+
+impl Stream {
+  /// Starts a new stream and returns the generation its ticks have to carry
+  pub fn start(&mut self, period_us: u32) -> u32 {
+    self.active = true;
+    self.period_us = period_us;
+    self.generation = self.generation.wrapping_add(1);
+    self.generation
+  }
+
+  pub fn stop(&mut self) {
+    self.active = false;
+    // invalidate any tick that is still in flight
+    self.generation = self.generation.wrapping_add(1);
+  }
+
+  pub fn is_current(&self, generation: u32) -> bool {
+    self.active && self.generation == generation
+  }
+
+  /// Keeps a running stream in step with a new data rate
+  pub fn retime(&mut self, rate: DataRate) {
+    if !self.active {
+      return;
+    }
+
+    match rate.period_us() {
+      Some(odr) => self.period_us = self.period_us.max(odr),
+      None => self.stop(),
+    }
+  }
+}
+
+impl State {
+  pub fn next(self, msg: &Message, config: &Configuration, stream: &Stream) -> (State, Action) {
+    match (self, msg) {
+      (State::Idling, Message::ReadID) => {
+        (State::Busy, Action::StartRead(registers::read::<WhoAmI>()))
+      }
+      (State::Idling, Message::ReadAxes) => {
+        (State::Busy, Action::StartRead(read_axes()))
+      }
+      (State::Idling, Message::ChangeScale(_)) |
+      (State::Idling, Message::ChangeBandwidth(_)) |
+      (State::Idling, Message::ChangeSelfTest(_)) => {
+        (State::Busy, Action::StartWrite(registers::write(config.with(msg).ctrl_reg5)))
+      }
+      (State::Idling, Message::ChangeDataRate(_)) |
+      (State::Idling, Message::ChangeBDU(_)) => {
+        (State::Busy, Action::StartWrite(registers::write(config.with(msg).ctrl_reg4)))
+      }
+      (State::Busy, Message::ReadComplete) => {
+        (State::Idling, Action::HandleData)
+      }
+      (State::Busy, Message::WriteComplete) => {
+        (State::Idling, Action::HandleData)
+      }
+      (_s, Message::CommandRejected) |
+      (_s, Message::TransferFailed(_)) => {
+        (State::Idling, Action::HandleError)
+      }
+      // the completion got lost on the way, the device is free again
+      (State::Busy, Message::Watchdog(_)) => {
+        (State::Idling, Action::HandleError)
+      }
+      (s, Message::StartStreaming { period_us }) => {
+        // there is no point in sampling faster than the device updates its outputs
+        match config.ctrl_reg4.data_rate.period_us() {
+          Some(odr) => (s, Action::StartStream(core::cmp::max(*period_us, odr))),
+          None => (s, Action::HandleError),
+        }
+      }
+      (s, Message::StopStreaming) => {
+        (s, Action::StopStream)
+      }
+      (s, Message::StreamTick(generation)) if !stream.is_current(*generation) => {
+        (s, Action::DoNothing)
+      }
+      (State::Idling, Message::StreamTick(_)) => {
+        (State::Busy, Action::StreamRead(read_axes()))
+      }
+      (State::Busy, Message::StreamTick(_)) => {
+        (State::Busy, Action::DropSample)
+      }
+      (s, _m) => {
+        (s, Action::DoNothing)
+      }
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::convert::TryFrom;
+  use registers::SpiMode;
+  use crate::spi_drv::transfer::{Transfer, Progress};
+
+  fn written(action: Action) -> spi_drv::Write {
+    match action {
+      Action::StartWrite(w) => w,
+      a => panic!("expected a write, got {:?}", a),
+    }
+  }
+
+  const SCALES: [(Scale, u8); 5] = [
+    (Scale::TwoG, 0x00),
+    (Scale::FourG, 0x08),
+    (Scale::SixG, 0x10),
+    (Scale::EightG, 0x18),
+    (Scale::SixteenG, 0x20),
+  ];
+
+  #[test]
+  fn change_scale_only_touches_the_scale_bits() {
+    let ctrl_reg5 = CtrlReg5 {
+      bandwidth: Bandwidth::FiftyHertz,
+      scale: Scale::TwoG,
+      self_test: SelfTest::Positive,
+      spi_mode: SpiMode::FourWire,
+    };
+    let config = Configuration { ctrl_reg5, ..Configuration::default() };
+
+    for &(scale, bits) in SCALES.iter() {
+      let (state, action) = State::Idling.next(&Message::ChangeScale(scale), &config, &Stream::default());
+      let w = written(action);
+      assert_eq!(state, State::Busy);
+      assert_eq!(w.reg, CtrlReg5::ADDRESS);
+      assert_eq!(w.data[0], 0xC2 | bits);
+    }
+  }
+
+  #[test]
+  fn change_bandwidth_and_self_test_keep_the_scale() {
+    let ctrl_reg5 = CtrlReg5 { scale: Scale::EightG, ..CtrlReg5::default() };
+    let config = Configuration { ctrl_reg5, ..Configuration::default() };
+
+    let (_, action) = State::Idling.next(&Message::ChangeBandwidth(Bandwidth::TwoHundredHertz), &config, &Stream::default());
+    assert_eq!(written(action).data[0], 0x58);
+
+    let (_, action) = State::Idling.next(&Message::ChangeSelfTest(SelfTest::Negative), &config, &Stream::default());
+    assert_eq!(written(action).data[0], 0x1C);
+  }
+
+  #[test]
+  fn completed_writes_update_the_configuration() {
+    let config = Configuration::default()
+      .with(&Message::ChangeScale(Scale::SixteenG))
+      .with(&Message::ChangeBDU(true))
+      .with(&Message::ChangeDataRate(DataRate::FiftyHertz));
+
+    assert_eq!(config.ctrl_reg5.scale, Scale::SixteenG);
+    assert_eq!(config.ctrl_reg4.data_rate, DataRate::FiftyHertz);
+    assert_eq!(u8::from(config.ctrl_reg4), 0x5F);
+  }
+
+  #[test]
+  fn data_rate_periods() {
+    assert_eq!(DataRate::Zero.period_us(), None);
+    assert_eq!(DataRate::ThreePointOneTwoFiveHertz.period_us(), Some(320_000));
+    assert_eq!(DataRate::OneHundredHertz.period_us(), Some(10_000));
+    assert_eq!(DataRate::SixteenHundredHertz.period_us(), Some(625));
+  }
+
+  #[test]
+  fn calculate_1g_uses_full_scale() {
+    assert_eq!(calculate_1g(0, &Scale::TwoG), 0.0);
+    assert_eq!(calculate_1g(16384, &Scale::TwoG), 1.0);
+    assert_eq!(calculate_1g(-32768, &Scale::TwoG), -2.0);
+    assert_eq!(calculate_1g(8192, &Scale::SixteenG), 4.0);
+  }
+
+  #[test]
+  fn read_axes_reads_all_six_output_registers() {
+    let (state, action) = State::Idling.next(&Message::ReadAxes, &Configuration::default(), &Stream::default());
+    assert_eq!(state, State::Busy);
+    assert!(matches!(action, Action::StartRead(spi_drv::Read { reg: 0xA8, len: 6, .. })));
+  }
+
+  #[test]
+  fn data_rate_keeps_axes_enabled() {
+    let (state, action) = State::Idling.next(&Message::ChangeDataRate(DataRate::OneHundredHertz), &Configuration::default(), &Stream::default());
+    let w = written(action);
+    assert_eq!(state, State::Busy);
+    assert_eq!(w.reg, CtrlReg4::ADDRESS);
+    assert_eq!(w.data[0], 0x67);
+  }
+
+  #[test]
+  fn bdu_keeps_data_rate() {
+    let config = Configuration { ctrl_reg4: CtrlReg4 { data_rate: DataRate::OneHundredHertz, ..CtrlReg4::default() }, ..Configuration::default() };
+    let (_, action) = State::Idling.next(&Message::ChangeBDU(true), &config, &Stream::default());
+    assert_eq!(written(action).data[0], 0x6F);
+  }
+
+  #[test]
+  fn commands_while_busy_are_ignored() {
+    let (state, action) = State::Busy.next(&Message::ReadID, &Configuration::default(), &Stream::default());
+    assert_eq!(state, State::Busy);
+    assert!(matches!(action, Action::DoNothing));
+  }
+
+  #[test]
+  fn completion_and_failure_return_to_idle() {
+    let config = Configuration::default();
+    let stream = Stream::default();
+    assert!(matches!(State::Busy.next(&Message::ReadComplete, &config, &stream), (State::Idling, Action::HandleData)));
+    assert!(matches!(State::Busy.next(&Message::WriteComplete, &config, &stream), (State::Idling, Action::HandleData)));
+    assert!(matches!(State::Busy.next(&Message::CommandRejected, &config, &stream), (State::Idling, Action::HandleError)));
+    let timeout = Message::TransferFailed(spi_drv::Error::Timeout);
+    assert!(matches!(State::Busy.next(&timeout, &config, &stream), (State::Idling, Action::HandleError)));
+  }
+
+  #[test]
+  fn watchdog_only_frees_a_busy_device() {
+    let config = Configuration::default();
+    let stream = Stream::default();
+
+    assert!(matches!(State::Busy.next(&Message::Watchdog(1), &config, &stream), (State::Idling, Action::HandleError)));
+    assert!(matches!(State::Idling.next(&Message::Watchdog(1), &config, &stream), (State::Idling, Action::DoNothing)));
+  }
+
+  #[test]
+  fn bus_completions_map_onto_messages() {
+    let completion = |result| spi_drv::Completion { id: 1, device: spi_drv::Device::Lis3dsh, result };
+
+    let read = completion(Ok(spi_drv::Response::Read(spi_drv::Bytes::new())));
+    assert!(matches!(Message::from(&read), Message::ReadComplete));
+    let written = completion(Ok(spi_drv::Response::Written));
+    assert!(matches!(Message::from(&written), Message::WriteComplete));
+    let rejected = completion(Err(spi_drv::Error::Rejected));
+    assert!(matches!(Message::from(&rejected), Message::CommandRejected));
+    let timed_out = completion(Err(spi_drv::Error::Timeout));
+    assert!(matches!(Message::from(&timed_out), Message::TransferFailed(spi_drv::Error::Timeout)));
+
+    assert!([read, written, rejected, timed_out].iter().all(|c| Message::from(c).is_completion()));
+    assert!(!Message::ReadAxes.is_completion());
+  }
+
+  fn hundred_hertz() -> Configuration {
+    Configuration { ctrl_reg4: CtrlReg4 { data_rate: DataRate::OneHundredHertz, ..CtrlReg4::default() }, ..Configuration::default() }
+  }
+
+  #[test]
+  fn streaming_is_limited_to_the_data_rate() {
+    let stream = Stream::default();
+    let (_, action) = State::Idling.next(&Message::StartStreaming { period_us: 1_000 }, &hundred_hertz(), &stream);
+    assert!(matches!(action, Action::StartStream(10_000)));
+
+    let (_, action) = State::Idling.next(&Message::StartStreaming { period_us: 250_000 }, &hundred_hertz(), &stream);
+    assert!(matches!(action, Action::StartStream(250_000)));
+  }
+
+  #[test]
+  fn streaming_needs_the_device_powered_up() {
+    let (_, action) = State::Idling.next(&Message::StartStreaming { period_us: 1_000 }, &Configuration::default(), &Stream::default());
+    assert!(matches!(action, Action::HandleError));
+  }
+
+  #[test]
+  fn ticks_read_axes_or_drop_while_busy() {
+    let mut stream = Stream::default();
+    let generation = stream.start(10_000);
+
+    let (state, action) = State::Idling.next(&Message::StreamTick(generation), &hundred_hertz(), &stream);
+    assert_eq!(state, State::Busy);
+    assert!(matches!(action, Action::StreamRead(spi_drv::Read { reg: 0xA8, len: 6, .. })));
+
+    let (state, action) = State::Busy.next(&Message::StreamTick(generation), &hundred_hertz(), &stream);
+    assert_eq!(state, State::Busy);
+    assert!(matches!(action, Action::DropSample));
+  }
+
+  #[test]
+  fn stale_ticks_are_ignored() {
+    let mut stream = Stream::default();
+    let old = stream.start(10_000);
+    let new = stream.start(20_000);
+    assert_ne!(old, new);
+
+    let (state, action) = State::Idling.next(&Message::StreamTick(old), &hundred_hertz(), &stream);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::DoNothing));
+
+    stream.stop();
+    let (_, action) = State::Idling.next(&Message::StreamTick(new), &hundred_hertz(), &stream);
+    assert!(matches!(action, Action::DoNothing));
+  }
+
+  #[test]
+  fn zero_period_streams_at_the_data_rate() {
+    let config = Configuration { ctrl_reg4: CtrlReg4 { data_rate: DataRate::FourHundredHertz, ..CtrlReg4::default() }, ..Configuration::default() };
+    let (_, action) = State::Idling.next(&Message::StartStreaming { period_us: 0 }, &config, &Stream::default());
+    assert!(matches!(action, Action::StartStream(2_500)));
+  }
+
+  #[test]
+  fn retiming_slows_the_stream_down_or_stops_it() {
+    let mut stream = Stream::default();
+    stream.start(2_500);
+
+    stream.retime(DataRate::FiftyHertz);
+    assert_eq!(stream.period_us, 20_000);
+
+    // a faster rate keeps the period that was asked for
+    stream.retime(DataRate::SixteenHundredHertz);
+    assert_eq!(stream.period_us, 20_000);
+
+    let generation = stream.generation;
+    stream.retime(DataRate::Zero);
+    assert!(!stream.is_current(generation));
+  }
+
+  #[test]
+  fn data_rate_change_reaches_the_device() {
+    let sim = sim::Lis3dshSim::new();
+    let (mut bus, mut cs) = sim.split();
+    let mut transfer = Transfer::new();
+
+    let msg = Message::ChangeDataRate(DataRate::FourHundredHertz);
+    let (_, action) = State::Idling.next(&msg, &Configuration::default(), &Stream::default());
+
+    transfer.start_write(&mut bus, &mut cs, written(action)).unwrap();
+    while transfer.continue_write(&mut bus, &mut cs).unwrap() != Progress::Done {}
+
+    let expected = Configuration::default().with(&msg).ctrl_reg4;
+    assert_eq!(CtrlReg4::try_from(sim.register(CtrlReg4::ADDRESS)), Ok(expected));
+  }
+
+  #[test]
+  fn axes_are_read_from_the_device() {
+    let sim = sim::Lis3dshSim::new();
+    sim.set_registers(OutX::ADDRESS, &[0x00, 0x40, 0x00, 0xC0, 0x00, 0x00]);
+    let (mut bus, mut cs) = sim.split();
+    let mut transfer = Transfer::new();
+
+    let (_, action) = State::Idling.next(&Message::ReadAxes, &Configuration::default(), &Stream::default());
+    let r = match action {
+      Action::StartRead(r) => r,
+      a => panic!("expected a read, got {:?}", a),
+    };
+
+    transfer.start_read(&mut bus, &mut cs, r).unwrap();
+    while transfer.continue_read(&mut bus, &mut cs).unwrap() != Progress::Done {}
+
+    let data = transfer.received();
+    let x = i16::from_le_bytes([data[0], data[1]]);
+    let y = i16::from_le_bytes([data[2], data[3]]);
+    assert_eq!(calculate_1g(x, &Scale::TwoG), 1.0);
+    assert_eq!(calculate_1g(y, &Scale::TwoG), -1.0);
+  }
+}
@@ -0,0 +1,989 @@
+use core::convert::TryFrom;
+
+use crate::spi_drv;
+use crate::spi_drv::Device;
+
+const READ_MASK: u8 = 0x80;
+const WRITE_MASK: u8 = 0x00;
+
+const DATA_RATE_BYTE_POS: u8 = 4;
+const BDU_BYTE_POS: u8 = 3;
+const Z_EN_BYTE_POS: u8 = 2;
+const Y_EN_BYTE_POS: u8 = 1;
+const X_EN_BYTE_POS: u8 = 0;
+
+const BANDWIDTH_BYTE_POS: u8 = 6;
+const SCALE_BYTE_POS: u8 = 3;
+const SELF_TEST_BYTE_POS: u8 = 1;
+const SPI_MODE_BYTE_POS: u8 = 0;
+
+const HYST_BYTE_POS: u8 = 5;
+const SM_PIN_BYTE_POS: u8 = 3;
+const SM_EN_BYTE_POS: u8 = 0;
+
+const DR_EN_BYTE_POS: u8 = 7;
+const IEA_BYTE_POS: u8 = 6;
+const IEL_BYTE_POS: u8 = 5;
+const INT2_EN_BYTE_POS: u8 = 4;
+const INT1_EN_BYTE_POS: u8 = 3;
+const VFILT_BYTE_POS: u8 = 2;
+const STRT_BYTE_POS: u8 = 0;
+
+const BOOT_BYTE_POS: u8 = 7;
+const FIFO_EN_BYTE_POS: u8 = 6;
+const WTM_EN_BYTE_POS: u8 = 5;
+const ADD_INC_BYTE_POS: u8 = 4;
+const P1_EMPTY_BYTE_POS: u8 = 3;
+const P1_WTM_BYTE_POS: u8 = 2;
+const P1_OVERRUN_BYTE_POS: u8 = 1;
+const P2_BOOT_BYTE_POS: u8 = 0;
+
+const ZYXOR_BYTE_POS: u8 = 7;
+const ZOR_BYTE_POS: u8 = 6;
+const YOR_BYTE_POS: u8 = 5;
+const XOR_BYTE_POS: u8 = 4;
+const ZYXDA_BYTE_POS: u8 = 3;
+const ZDA_BYTE_POS: u8 = 2;
+const YDA_BYTE_POS: u8 = 1;
+const XDA_BYTE_POS: u8 = 0;
+
+const FIFO_MODE_BYTE_POS: u8 = 5;
+const WTM_BYTE_POS: u8 = 7;
+const OVRN_BYTE_POS: u8 = 6;
+const EMPTY_BYTE_POS: u8 = 5;
+
+const DATA_RATE_MASK: u8 = 0xF0;
+const BANDWIDTH_MASK: u8 = 0xC0;
+const SCALE_MASK: u8 = 0x38;
+const SELF_TEST_MASK: u8 = 0x06;
+const SPI_MODE_MASK: u8 = 0x01;
+const HYST_MASK: u8 = 0xE0;
+const FIFO_MODE_MASK: u8 = 0xE0;
+const FIFO_LEVEL_MASK: u8 = 0x1F;
+
+/// A register in the LIS3DSH address map. Multi-byte registers start at the
+/// low byte and are read with address auto-increment.
+pub trait Register {
+  const ADDRESS: u8;
+  const SIZE: u8 = 1;
+}
+
+/// Registers the device allows us to read
+pub trait Readable: Register {}
+
+/// Registers the device allows us to write
+pub trait Writable: Register {}
+
+/// Reads the whole of `R`
+pub fn read<R: Readable>() -> spi_drv::Read {
+  spi_drv::Read { device: Device::Lis3dsh, reg: READ_MASK | R::ADDRESS, len: R::SIZE }
+}
+
+/// Reads `len` consecutive bytes starting at `R`
+pub fn read_burst<R: Readable>(len: u8) -> spi_drv::Read {
+  spi_drv::Read { device: Device::Lis3dsh, reg: READ_MASK | R::ADDRESS, len }
+}
+
+/// Writes a register that is modelled as a bitfield
+pub fn write<R: Writable + Into<u8>>(value: R) -> spi_drv::Write {
+  write_raw::<R>(value.into())
+}
+
+/// Writes a register that holds a plain value
+pub fn write_raw<R: Writable>(value: u8) -> spi_drv::Write {
+  let mut data = [0; spi_drv::TX_BUFFER_SIZE];
+  data[0] = value;
+
+  // the length includes the address byte
+  spi_drv::Write { device: Device::Lis3dsh, reg: WRITE_MASK | R::ADDRESS, len: 2, data }
+}
+
+macro_rules! registers {
+  ($($(#[$doc:meta])* $name:ident: $addr:expr, $size:expr, $($access:ident)+;)*) => {
+    $(
+      $(#[$doc])*
+      #[derive(Debug)]
+      pub struct $name;
+
+      impl Register for $name {
+        const ADDRESS: u8 = $addr;
+        const SIZE: u8 = $size;
+      }
+
+      $(impl $access for $name {})+
+    )*
+  };
+}
+
+registers! {
+  /// Temperature, 1 LSB/deg with 0 at 25 deg
+  OutT: 0x0C, 1, Readable;
+  Info1: 0x0D, 1, Readable;
+  Info2: 0x0E, 1, Readable;
+  WhoAmI: 0x0F, 1, Readable;
+  OffX: 0x10, 1, Readable Writable;
+  OffY: 0x11, 1, Readable Writable;
+  OffZ: 0x12, 1, Readable Writable;
+  /// Constant shift applied to the axis in the state machines
+  CsX: 0x13, 1, Readable Writable;
+  CsY: 0x14, 1, Readable Writable;
+  CsZ: 0x15, 1, Readable Writable;
+  /// Long counter
+  Lc: 0x16, 2, Readable Writable;
+  /// Interrupt synchronization status
+  Stat: 0x18, 1, Readable;
+  Peak1: 0x19, 1, Readable;
+  Peak2: 0x1A, 1, Readable;
+  Vfc1: 0x1B, 1, Readable Writable;
+  Vfc2: 0x1C, 1, Readable Writable;
+  Vfc3: 0x1D, 1, Readable Writable;
+  Vfc4: 0x1E, 1, Readable Writable;
+  Thrs3: 0x1F, 1, Readable Writable;
+  OutX: 0x28, 2, Readable;
+  OutY: 0x2A, 2, Readable;
+  OutZ: 0x2C, 2, Readable;
+  /// State machine 1 program, 16 opcodes
+  St1: 0x40, 16, Writable;
+  Tim4_1: 0x50, 1, Writable;
+  Tim3_1: 0x51, 1, Writable;
+  Tim2_1: 0x52, 2, Writable;
+  Tim1_1: 0x54, 2, Writable;
+  Thrs2_1: 0x56, 1, Writable;
+  Thrs1_1: 0x57, 1, Writable;
+  Mask1B: 0x59, 1, Writable;
+  Mask1A: 0x5A, 1, Writable;
+  Sett1: 0x5B, 1, Writable;
+  /// State machine 1 program and reset pointers
+  Pr1: 0x5C, 1, Readable;
+  Tc1: 0x5D, 2, Readable;
+  Outs1: 0x5F, 1, Readable;
+  /// State machine 2 program, 16 opcodes
+  St2: 0x60, 16, Writable;
+  Tim4_2: 0x70, 1, Writable;
+  Tim3_2: 0x71, 1, Writable;
+  Tim2_2: 0x72, 2, Writable;
+  Tim1_2: 0x74, 2, Writable;
+  Thrs2_2: 0x76, 1, Writable;
+  Thrs1_2: 0x77, 1, Writable;
+  /// Decimation factor for state machine 2
+  Des2: 0x78, 1, Writable;
+  Mask2B: 0x79, 1, Writable;
+  Mask2A: 0x7A, 1, Writable;
+  Sett2: 0x7B, 1, Writable;
+  Pr2: 0x7C, 1, Readable;
+  Tc2: 0x7D, 2, Readable;
+  Outs2: 0x7F, 1, Readable;
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DataRate {
+  Zero,
+  ThreePointOneTwoFiveHertz,
+  SixPointTwoFiveHertz,
+  TwelvePointFiveHertz,
+  TwentyFiveHertz,
+  FiftyHertz,
+  OneHundredHertz,
+  FourHundredHertz,
+  EightHundredHertz,
+  SixteenHundredHertz,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Scale {
+  TwoG,
+  FourG,
+  SixG,
+  EightG,
+  SixteenG,
+}
+
+/// Anti-aliasing filter bandwidth
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Bandwidth {
+  EightHundredHertz,
+  FourHundredHertz,
+  TwoHundredHertz,
+  FiftyHertz,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SelfTest {
+  Normal,
+  Positive,
+  Negative,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpiMode {
+  FourWire,
+  ThreeWire,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FifoMode {
+  Bypass,
+  Fifo,
+  Stream,
+  StreamToFifo,
+  BypassToStream,
+  BypassToFifo,
+}
+
+/// Contents of CTRL_REG4
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CtrlReg4 {
+  pub data_rate: DataRate,
+  // block data update
+  pub bdu: bool,
+  pub z_en: bool,
+  pub y_en: bool,
+  pub x_en: bool,
+}
+
+/// Contents of CTRL_REG1, the state machine 1 control register
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CtrlReg1 {
+  // hysteresis added to the thresholds, 3 bits
+  pub hyst: u8,
+  // route the interrupt to INT2 instead of INT1
+  pub sm_pin: bool,
+  pub sm_en: bool,
+}
+
+/// Contents of CTRL_REG2, the state machine 2 control register
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CtrlReg2 {
+  pub hyst: u8,
+  pub sm_pin: bool,
+  pub sm_en: bool,
+}
+
+/// Contents of CTRL_REG3
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CtrlReg3 {
+  // data ready signal on INT1
+  pub dr_en: bool,
+  // interrupt pins active high
+  pub iea: bool,
+  // latched instead of pulsed interrupts
+  pub iel: bool,
+  pub int2_en: bool,
+  pub int1_en: bool,
+  // vector filter
+  pub vfilt: bool,
+  // soft reset, clears itself
+  pub strt: bool,
+}
+
+/// Contents of CTRL_REG5
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CtrlReg5 {
+  pub bandwidth: Bandwidth,
+  pub scale: Scale,
+  pub self_test: SelfTest,
+  // the board wires the accelerometer for 4-wire SPI
+  pub spi_mode: SpiMode,
+}
+
+/// Contents of CTRL_REG6
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CtrlReg6 {
+  // reboot memory content, clears itself
+  pub boot: bool,
+  pub fifo_en: bool,
+  // stop filling the FIFO at the watermark
+  pub wtm_en: bool,
+  // address auto-increment for multi-byte transfers
+  pub add_inc: bool,
+  pub p1_empty: bool,
+  pub p1_wtm: bool,
+  pub p1_overrun: bool,
+  pub p2_boot: bool,
+}
+
+/// Contents of STATUS
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Status {
+  pub zyxor: bool,
+  pub zor: bool,
+  pub yor: bool,
+  pub xor: bool,
+  pub zyxda: bool,
+  pub zda: bool,
+  pub yda: bool,
+  pub xda: bool,
+}
+
+/// Contents of FIFO_CTRL
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FifoCtrl {
+  pub mode: FifoMode,
+  // watermark level, 5 bits
+  pub watermark: u8,
+}
+
+/// Contents of FIFO_SRC
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FifoSrc {
+  pub wtm: bool,
+  pub ovrn: bool,
+  pub empty: bool,
+  // number of unread samples, 5 bits
+  pub level: u8,
+}
+
+
+fn flag(set: bool, pos: u8) -> u8 {
+  u8::from(set) << pos
+}
+
+fn is_set(x: u8, pos: u8) -> bool {
+  x & (1 << pos) != 0
+}
+
+impl Register for CtrlReg4 {
+  const ADDRESS: u8 = 0x20;
+}
+impl Readable for CtrlReg4 {}
+impl Writable for CtrlReg4 {}
+
+impl Register for CtrlReg1 {
+  const ADDRESS: u8 = 0x21;
+}
+impl Readable for CtrlReg1 {}
+impl Writable for CtrlReg1 {}
+
+impl Register for CtrlReg2 {
+  const ADDRESS: u8 = 0x22;
+}
+impl Readable for CtrlReg2 {}
+impl Writable for CtrlReg2 {}
+
+impl Register for CtrlReg3 {
+  const ADDRESS: u8 = 0x23;
+}
+impl Readable for CtrlReg3 {}
+impl Writable for CtrlReg3 {}
+
+impl Register for CtrlReg5 {
+  const ADDRESS: u8 = 0x24;
+}
+impl Readable for CtrlReg5 {}
+impl Writable for CtrlReg5 {}
+
+impl Register for CtrlReg6 {
+  const ADDRESS: u8 = 0x25;
+}
+impl Readable for CtrlReg6 {}
+impl Writable for CtrlReg6 {}
+
+impl Register for Status {
+  const ADDRESS: u8 = 0x27;
+}
+impl Readable for Status {}
+
+impl Register for FifoCtrl {
+  const ADDRESS: u8 = 0x2E;
+}
+impl Readable for FifoCtrl {}
+impl Writable for FifoCtrl {}
+
+impl Register for FifoSrc {
+  const ADDRESS: u8 = 0x2F;
+}
+impl Readable for FifoSrc {}
+
+impl From<DataRate> for u8 {
+  fn from(x: DataRate) -> Self {
+    match x {
+      DataRate::Zero => 0 << DATA_RATE_BYTE_POS,
+      DataRate::ThreePointOneTwoFiveHertz => 1 << DATA_RATE_BYTE_POS,
+      DataRate::SixPointTwoFiveHertz => 2 << DATA_RATE_BYTE_POS,
+      DataRate::TwelvePointFiveHertz => 3 << DATA_RATE_BYTE_POS,
+      DataRate::TwentyFiveHertz => 4 << DATA_RATE_BYTE_POS,
+      DataRate::FiftyHertz => 5 << DATA_RATE_BYTE_POS,
+      DataRate::OneHundredHertz => 6 << DATA_RATE_BYTE_POS,
+      DataRate::FourHundredHertz => 7 << DATA_RATE_BYTE_POS,
+      DataRate::EightHundredHertz => 8 << DATA_RATE_BYTE_POS,
+      DataRate::SixteenHundredHertz => 9 << DATA_RATE_BYTE_POS,
+    }
+  }
+}
+
+/// Decodes the output data rate from a CTRL_REG4 value, the error holds the
+/// unused ODR setting that was found
+impl TryFrom<u8> for DataRate {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    match (x & DATA_RATE_MASK) >> DATA_RATE_BYTE_POS {
+      0 => Ok(DataRate::Zero),
+      1 => Ok(DataRate::ThreePointOneTwoFiveHertz),
+      2 => Ok(DataRate::SixPointTwoFiveHertz),
+      3 => Ok(DataRate::TwelvePointFiveHertz),
+      4 => Ok(DataRate::TwentyFiveHertz),
+      5 => Ok(DataRate::FiftyHertz),
+      6 => Ok(DataRate::OneHundredHertz),
+      7 => Ok(DataRate::FourHundredHertz),
+      8 => Ok(DataRate::EightHundredHertz),
+      9 => Ok(DataRate::SixteenHundredHertz),
+      odr => Err(odr),
+    }
+  }
+}
+
+impl From<Scale> for u8 {
+  fn from(x: Scale) -> Self {
+    match x {
+      Scale::TwoG => 0 << SCALE_BYTE_POS,
+      Scale::FourG => 1 << SCALE_BYTE_POS,
+      Scale::SixG => 2 << SCALE_BYTE_POS,
+      Scale::EightG => 3 << SCALE_BYTE_POS,
+      Scale::SixteenG => 4 << SCALE_BYTE_POS,
+    }
+  }
+}
+
+impl TryFrom<u8> for Scale {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    match (x & SCALE_MASK) >> SCALE_BYTE_POS {
+      0 => Ok(Scale::TwoG),
+      1 => Ok(Scale::FourG),
+      2 => Ok(Scale::SixG),
+      3 => Ok(Scale::EightG),
+      4 => Ok(Scale::SixteenG),
+      fscale => Err(fscale),
+    }
+  }
+}
+
+impl From<Bandwidth> for u8 {
+  fn from(x: Bandwidth) -> Self {
+    match x {
+      Bandwidth::EightHundredHertz => 0 << BANDWIDTH_BYTE_POS,
+      Bandwidth::TwoHundredHertz => 1 << BANDWIDTH_BYTE_POS,
+      Bandwidth::FourHundredHertz => 2 << BANDWIDTH_BYTE_POS,
+      Bandwidth::FiftyHertz => 3 << BANDWIDTH_BYTE_POS,
+    }
+  }
+}
+
+impl From<u8> for Bandwidth {
+  fn from(x: u8) -> Self {
+    match (x & BANDWIDTH_MASK) >> BANDWIDTH_BYTE_POS {
+      0 => Bandwidth::EightHundredHertz,
+      1 => Bandwidth::TwoHundredHertz,
+      2 => Bandwidth::FourHundredHertz,
+      _ => Bandwidth::FiftyHertz,
+    }
+  }
+}
+
+impl From<SelfTest> for u8 {
+  fn from(x: SelfTest) -> Self {
+    match x {
+      SelfTest::Normal => 0 << SELF_TEST_BYTE_POS,
+      SelfTest::Positive => 1 << SELF_TEST_BYTE_POS,
+      SelfTest::Negative => 2 << SELF_TEST_BYTE_POS,
+    }
+  }
+}
+
+impl TryFrom<u8> for SelfTest {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    match (x & SELF_TEST_MASK) >> SELF_TEST_BYTE_POS {
+      0 => Ok(SelfTest::Normal),
+      1 => Ok(SelfTest::Positive),
+      2 => Ok(SelfTest::Negative),
+      st => Err(st),
+    }
+  }
+}
+
+impl From<SpiMode> for u8 {
+  fn from(x: SpiMode) -> Self {
+    match x {
+      SpiMode::FourWire => 0 << SPI_MODE_BYTE_POS,
+      SpiMode::ThreeWire => 1 << SPI_MODE_BYTE_POS,
+    }
+  }
+}
+
+impl From<u8> for SpiMode {
+  fn from(x: u8) -> Self {
+    match (x & SPI_MODE_MASK) >> SPI_MODE_BYTE_POS {
+      0 => SpiMode::FourWire,
+      _ => SpiMode::ThreeWire,
+    }
+  }
+}
+
+impl From<FifoMode> for u8 {
+  fn from(x: FifoMode) -> Self {
+    match x {
+      FifoMode::Bypass => 0 << FIFO_MODE_BYTE_POS,
+      FifoMode::Fifo => 1 << FIFO_MODE_BYTE_POS,
+      FifoMode::Stream => 2 << FIFO_MODE_BYTE_POS,
+      FifoMode::StreamToFifo => 3 << FIFO_MODE_BYTE_POS,
+      FifoMode::BypassToStream => 4 << FIFO_MODE_BYTE_POS,
+      FifoMode::BypassToFifo => 7 << FIFO_MODE_BYTE_POS,
+    }
+  }
+}
+
+impl TryFrom<u8> for FifoMode {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    match (x & FIFO_MODE_MASK) >> FIFO_MODE_BYTE_POS {
+      0 => Ok(FifoMode::Bypass),
+      1 => Ok(FifoMode::Fifo),
+      2 => Ok(FifoMode::Stream),
+      3 => Ok(FifoMode::StreamToFifo),
+      4 => Ok(FifoMode::BypassToStream),
+      7 => Ok(FifoMode::BypassToFifo),
+      fmode => Err(fmode),
+    }
+  }
+}
+
+impl From<CtrlReg4> for u8 {
+  fn from(x: CtrlReg4) -> Self {
+    u8::from(x.data_rate) |
+      flag(x.bdu, BDU_BYTE_POS) |
+      flag(x.z_en, Z_EN_BYTE_POS) |
+      flag(x.y_en, Y_EN_BYTE_POS) |
+      flag(x.x_en, X_EN_BYTE_POS)
+  }
+}
+
+/// The error holds the register value when it contains an unused data rate
+impl TryFrom<u8> for CtrlReg4 {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    Ok(CtrlReg4 {
+      data_rate: DataRate::try_from(x).map_err(|_| x)?,
+      bdu: is_set(x, BDU_BYTE_POS),
+      z_en: is_set(x, Z_EN_BYTE_POS),
+      y_en: is_set(x, Y_EN_BYTE_POS),
+      x_en: is_set(x, X_EN_BYTE_POS),
+    })
+  }
+}
+
+impl From<CtrlReg1> for u8 {
+  fn from(x: CtrlReg1) -> Self {
+    ((x.hyst << HYST_BYTE_POS) & HYST_MASK) |
+      flag(x.sm_pin, SM_PIN_BYTE_POS) |
+      flag(x.sm_en, SM_EN_BYTE_POS)
+  }
+}
+
+impl From<u8> for CtrlReg1 {
+  fn from(x: u8) -> Self {
+    CtrlReg1 {
+      hyst: (x & HYST_MASK) >> HYST_BYTE_POS,
+      sm_pin: is_set(x, SM_PIN_BYTE_POS),
+      sm_en: is_set(x, SM_EN_BYTE_POS),
+    }
+  }
+}
+
+impl From<CtrlReg2> for u8 {
+  fn from(x: CtrlReg2) -> Self {
+    ((x.hyst << HYST_BYTE_POS) & HYST_MASK) |
+      flag(x.sm_pin, SM_PIN_BYTE_POS) |
+      flag(x.sm_en, SM_EN_BYTE_POS)
+  }
+}
+
+impl From<u8> for CtrlReg2 {
+  fn from(x: u8) -> Self {
+    CtrlReg2 {
+      hyst: (x & HYST_MASK) >> HYST_BYTE_POS,
+      sm_pin: is_set(x, SM_PIN_BYTE_POS),
+      sm_en: is_set(x, SM_EN_BYTE_POS),
+    }
+  }
+}
+
+impl From<CtrlReg3> for u8 {
+  fn from(x: CtrlReg3) -> Self {
+    flag(x.dr_en, DR_EN_BYTE_POS) |
+      flag(x.iea, IEA_BYTE_POS) |
+      flag(x.iel, IEL_BYTE_POS) |
+      flag(x.int2_en, INT2_EN_BYTE_POS) |
+      flag(x.int1_en, INT1_EN_BYTE_POS) |
+      flag(x.vfilt, VFILT_BYTE_POS) |
+      flag(x.strt, STRT_BYTE_POS)
+  }
+}
+
+impl From<u8> for CtrlReg3 {
+  fn from(x: u8) -> Self {
+    CtrlReg3 {
+      dr_en: is_set(x, DR_EN_BYTE_POS),
+      iea: is_set(x, IEA_BYTE_POS),
+      iel: is_set(x, IEL_BYTE_POS),
+      int2_en: is_set(x, INT2_EN_BYTE_POS),
+      int1_en: is_set(x, INT1_EN_BYTE_POS),
+      vfilt: is_set(x, VFILT_BYTE_POS),
+      strt: is_set(x, STRT_BYTE_POS),
+    }
+  }
+}
+
+impl From<CtrlReg5> for u8 {
+  fn from(x: CtrlReg5) -> Self {
+    u8::from(x.bandwidth) |
+      u8::from(x.scale) |
+      u8::from(x.self_test) |
+      u8::from(x.spi_mode)
+  }
+}
+
+/// Decodes a CTRL_REG5 value, the error holds the register value when it
+/// contains a reserved full scale or self-test setting
+impl TryFrom<u8> for CtrlReg5 {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    Ok(CtrlReg5 {
+      bandwidth: Bandwidth::from(x),
+      scale: Scale::try_from(x).map_err(|_| x)?,
+      self_test: SelfTest::try_from(x).map_err(|_| x)?,
+      spi_mode: SpiMode::from(x),
+    })
+  }
+}
+
+impl From<CtrlReg6> for u8 {
+  fn from(x: CtrlReg6) -> Self {
+    flag(x.boot, BOOT_BYTE_POS) |
+      flag(x.fifo_en, FIFO_EN_BYTE_POS) |
+      flag(x.wtm_en, WTM_EN_BYTE_POS) |
+      flag(x.add_inc, ADD_INC_BYTE_POS) |
+      flag(x.p1_empty, P1_EMPTY_BYTE_POS) |
+      flag(x.p1_wtm, P1_WTM_BYTE_POS) |
+      flag(x.p1_overrun, P1_OVERRUN_BYTE_POS) |
+      flag(x.p2_boot, P2_BOOT_BYTE_POS)
+  }
+}
+
+impl From<u8> for CtrlReg6 {
+  fn from(x: u8) -> Self {
+    CtrlReg6 {
+      boot: is_set(x, BOOT_BYTE_POS),
+      fifo_en: is_set(x, FIFO_EN_BYTE_POS),
+      wtm_en: is_set(x, WTM_EN_BYTE_POS),
+      add_inc: is_set(x, ADD_INC_BYTE_POS),
+      p1_empty: is_set(x, P1_EMPTY_BYTE_POS),
+      p1_wtm: is_set(x, P1_WTM_BYTE_POS),
+      p1_overrun: is_set(x, P1_OVERRUN_BYTE_POS),
+      p2_boot: is_set(x, P2_BOOT_BYTE_POS),
+    }
+  }
+}
+
+impl From<u8> for Status {
+  fn from(x: u8) -> Self {
+    Status {
+      zyxor: is_set(x, ZYXOR_BYTE_POS),
+      zor: is_set(x, ZOR_BYTE_POS),
+      yor: is_set(x, YOR_BYTE_POS),
+      xor: is_set(x, XOR_BYTE_POS),
+      zyxda: is_set(x, ZYXDA_BYTE_POS),
+      zda: is_set(x, ZDA_BYTE_POS),
+      yda: is_set(x, YDA_BYTE_POS),
+      xda: is_set(x, XDA_BYTE_POS),
+    }
+  }
+}
+
+impl From<FifoCtrl> for u8 {
+  fn from(x: FifoCtrl) -> Self {
+    u8::from(x.mode) | (x.watermark & FIFO_LEVEL_MASK)
+  }
+}
+
+/// The error holds the register value when it contains an unused FIFO mode
+impl TryFrom<u8> for FifoCtrl {
+  type Error = u8;
+
+  fn try_from(x: u8) -> Result<Self, Self::Error> {
+    Ok(FifoCtrl {
+      mode: FifoMode::try_from(x).map_err(|_| x)?,
+      watermark: x & FIFO_LEVEL_MASK,
+    })
+  }
+}
+
+impl From<u8> for FifoSrc {
+  fn from(x: u8) -> Self {
+    FifoSrc {
+      wtm: is_set(x, WTM_BYTE_POS),
+      ovrn: is_set(x, OVRN_BYTE_POS),
+      empty: is_set(x, EMPTY_BYTE_POS),
+      level: x & FIFO_LEVEL_MASK,
+    }
+  }
+}
+
+/// Reset value with all axes enabled
+impl Default for CtrlReg4 {
+  fn default() -> Self {
+    CtrlReg4 {
+      data_rate: DataRate::Zero,
+      bdu: false,
+      z_en: true,
+      y_en: true,
+      x_en: true,
+    }
+  }
+}
+
+impl Default for CtrlReg5 {
+  fn default() -> Self {
+    CtrlReg5 {
+      bandwidth: Bandwidth::EightHundredHertz,
+      scale: Scale::TwoG,
+      self_test: SelfTest::Normal,
+      spi_mode: SpiMode::FourWire,
+    }
+  }
+}
+
+/// Reset value with address auto-increment enabled
+impl Default for CtrlReg6 {
+  fn default() -> Self {
+    CtrlReg6::from(1 << ADD_INC_BYTE_POS)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RATES: [DataRate; 10] = [
+    DataRate::Zero,
+    DataRate::ThreePointOneTwoFiveHertz,
+    DataRate::SixPointTwoFiveHertz,
+    DataRate::TwelvePointFiveHertz,
+    DataRate::TwentyFiveHertz,
+    DataRate::FiftyHertz,
+    DataRate::OneHundredHertz,
+    DataRate::FourHundredHertz,
+    DataRate::EightHundredHertz,
+    DataRate::SixteenHundredHertz,
+  ];
+
+  const SCALES: [(Scale, u8); 5] = [
+    (Scale::TwoG, 0x00),
+    (Scale::FourG, 0x08),
+    (Scale::SixG, 0x10),
+    (Scale::EightG, 0x18),
+    (Scale::SixteenG, 0x20),
+  ];
+
+  const BANDWIDTHS: [(Bandwidth, u8); 4] = [
+    (Bandwidth::EightHundredHertz, 0x00),
+    (Bandwidth::TwoHundredHertz, 0x40),
+    (Bandwidth::FourHundredHertz, 0x80),
+    (Bandwidth::FiftyHertz, 0xC0),
+  ];
+
+  const SELF_TESTS: [(SelfTest, u8); 3] = [
+    (SelfTest::Normal, 0x00),
+    (SelfTest::Positive, 0x02),
+    (SelfTest::Negative, 0x04),
+  ];
+
+  const FIFO_MODES: [(FifoMode, u8); 6] = [
+    (FifoMode::Bypass, 0x00),
+    (FifoMode::Fifo, 0x20),
+    (FifoMode::Stream, 0x40),
+    (FifoMode::StreamToFifo, 0x60),
+    (FifoMode::BypassToStream, 0x80),
+    (FifoMode::BypassToFifo, 0xE0),
+  ];
+
+  #[test]
+  fn transfers_carry_the_access_bit() {
+    let r = read::<WhoAmI>();
+    assert_eq!((r.reg, r.len), (0x8F, 1));
+
+    let r = read::<OutX>();
+    assert_eq!((r.reg, r.len), (0xA8, 2));
+
+    let r = read_burst::<OutX>(6);
+    assert_eq!((r.reg, r.len), (0xA8, 6));
+
+    let w = write_raw::<OffX>(0x12);
+    assert_eq!((w.reg, w.len, w.data[0]), (0x10, 2, 0x12));
+
+    let w = write(CtrlReg4::default());
+    assert_eq!((w.reg, w.len, w.data[0]), (0x20, 2, 0x07));
+  }
+
+  #[test]
+  fn control_register_addresses() {
+    assert_eq!(CtrlReg1::ADDRESS, 0x21);
+    assert_eq!(CtrlReg2::ADDRESS, 0x22);
+    assert_eq!(CtrlReg3::ADDRESS, 0x23);
+    assert_eq!(CtrlReg4::ADDRESS, 0x20);
+    assert_eq!(CtrlReg5::ADDRESS, 0x24);
+    assert_eq!(CtrlReg6::ADDRESS, 0x25);
+    assert_eq!(Status::ADDRESS, 0x27);
+    assert_eq!(FifoCtrl::ADDRESS, 0x2E);
+    assert_eq!(FifoSrc::ADDRESS, 0x2F);
+    assert_eq!(St1::SIZE, 16);
+    assert_eq!(Outs2::ADDRESS, 0x7F);
+  }
+
+  #[test]
+  fn data_rate_encoding() {
+    for (odr, rate) in RATES.iter().enumerate() {
+      assert_eq!(u8::from(*rate), (odr as u8) << 4);
+    }
+  }
+
+  #[test]
+  fn data_rate_decoding_ignores_axis_and_bdu_bits() {
+    for rate in RATES.iter() {
+      let reg = u8::from(*rate) | 0x0F;
+      assert_eq!(DataRate::try_from(reg), Ok(*rate));
+    }
+    assert_eq!(DataRate::try_from(0xA7), Err(0x0A));
+    assert_eq!(DataRate::try_from(0xF0), Err(0x0F));
+  }
+
+  #[test]
+  fn scale_encoding() {
+    for &(scale, bits) in SCALES.iter() {
+      assert_eq!(u8::from(scale), bits);
+      assert_eq!(Scale::try_from(bits | !SCALE_MASK), Ok(scale));
+    }
+    assert_eq!(Scale::try_from(0x28), Err(5));
+    assert_eq!(Scale::try_from(0x38), Err(7));
+  }
+
+  #[test]
+  fn bandwidth_encoding() {
+    for &(bandwidth, bits) in BANDWIDTHS.iter() {
+      assert_eq!(u8::from(bandwidth), bits);
+      assert_eq!(Bandwidth::from(bits | !BANDWIDTH_MASK), bandwidth);
+    }
+  }
+
+  #[test]
+  fn self_test_encoding() {
+    for &(self_test, bits) in SELF_TESTS.iter() {
+      assert_eq!(u8::from(self_test), bits);
+      assert_eq!(SelfTest::try_from(bits | !SELF_TEST_MASK), Ok(self_test));
+    }
+    assert_eq!(SelfTest::try_from(0x06), Err(3));
+  }
+
+  #[test]
+  fn spi_mode_encoding() {
+    assert_eq!(u8::from(SpiMode::FourWire), 0x00);
+    assert_eq!(u8::from(SpiMode::ThreeWire), 0x01);
+    assert_eq!(SpiMode::from(0xFE), SpiMode::FourWire);
+    assert_eq!(SpiMode::from(0x01), SpiMode::ThreeWire);
+  }
+
+  #[test]
+  fn ctrl_reg4_round_trips() {
+    for rate in RATES.iter() {
+      for bits in 0..0x10u8 {
+        let reg = u8::from(*rate) | bits;
+        assert_eq!(CtrlReg4::try_from(reg).map(u8::from), Ok(reg));
+      }
+    }
+
+    assert_eq!(u8::from(CtrlReg4::default()), 0x07);
+    assert_eq!(CtrlReg4::try_from(0xB7), Err(0xB7));
+  }
+
+  #[test]
+  fn ctrl_reg5_round_trips() {
+    for &(bandwidth, _) in BANDWIDTHS.iter() {
+      for &(scale, _) in SCALES.iter() {
+        for &(self_test, _) in SELF_TESTS.iter() {
+          for &spi_mode in [SpiMode::FourWire, SpiMode::ThreeWire].iter() {
+            let reg = CtrlReg5 { bandwidth, scale, self_test, spi_mode };
+            assert_eq!(CtrlReg5::try_from(u8::from(reg)), Ok(reg));
+          }
+        }
+      }
+    }
+
+    assert_eq!(u8::from(CtrlReg5::default()), 0x00);
+    assert_eq!(CtrlReg5::try_from(0x06), Err(0x06));
+  }
+
+  #[test]
+  fn state_machine_control_round_trips() {
+    // bits 4, 2 and 1 are unused
+    for reg in (0..=0xFFu8).filter(|r| r & 0x16 == 0) {
+      assert_eq!(u8::from(CtrlReg1::from(reg)), reg);
+      assert_eq!(u8::from(CtrlReg2::from(reg)), reg);
+    }
+
+    let reg = CtrlReg1 { hyst: 5, sm_pin: true, sm_en: true };
+    assert_eq!(u8::from(reg), 0xA9);
+  }
+
+  #[test]
+  fn ctrl_reg3_round_trips() {
+    // bit 1 is unused
+    for reg in (0..=0xFFu8).filter(|r| r & 0x02 == 0) {
+      assert_eq!(u8::from(CtrlReg3::from(reg)), reg);
+    }
+
+    assert!(CtrlReg3::from(0x80).dr_en);
+    assert!(CtrlReg3::from(0x01).strt);
+  }
+
+  #[test]
+  fn ctrl_reg6_round_trips() {
+    for reg in 0..=0xFFu8 {
+      assert_eq!(u8::from(CtrlReg6::from(reg)), reg);
+    }
+
+    assert!(CtrlReg6::default().add_inc);
+    assert_eq!(u8::from(CtrlReg6::default()), 0x10);
+  }
+
+  #[test]
+  fn status_flags() {
+    let status = Status::from(0x88);
+    assert!(status.zyxor && status.zyxda);
+    assert!(!status.xda && !status.xor);
+
+    let status = Status::from(0x01);
+    assert!(status.xda);
+    assert!(!status.zyxda);
+  }
+
+  #[test]
+  fn fifo_encoding() {
+    for &(mode, bits) in FIFO_MODES.iter() {
+      assert_eq!(u8::from(mode), bits);
+      assert_eq!(FifoCtrl::try_from(bits | 0x1F), Ok(FifoCtrl { mode, watermark: 31 }));
+    }
+    assert_eq!(FifoCtrl::try_from(0xA0), Err(0xA0));
+    assert_eq!(u8::from(FifoCtrl { mode: FifoMode::Stream, watermark: 0xFF }), 0x5F);
+
+    let src = FifoSrc::from(0xB3);
+    assert!(src.wtm && !src.ovrn && src.empty);
+    assert_eq!(src.level, 0x13);
+  }
+}
@@ -0,0 +1,159 @@
+//! In-memory LIS3DSH that sits on the other end of the SPI bus in host tests.
+
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use embedded_hal::{
+  spi::FullDuplex,
+  digital::v2::OutputPin,
+};
+
+const READ_MASK: u8 = 0x80;
+const ADDRESS_MASK: u8 = 0x7F;
+const ADD_INC_MASK: u8 = 0x10;
+const CTRL_REG6: usize = 0x25;
+
+// shifted out while the address byte is clocked in
+const GARBAGE: u8 = 0xFF;
+
+struct Device {
+  registers: [u8; 128],
+  selected: bool,
+  // address byte of the current transaction, `None` until it has been received
+  command: Option<u8>,
+  address: u8,
+  rx: Option<u8>,
+  mosi: Vec<u8>,
+  transactions: usize,
+}
+
+/// Shared handle to the simulated device
+#[derive(Clone)]
+pub struct Lis3dshSim(Rc<RefCell<Device>>);
+
+/// MOSI/MISO side of the simulated device
+pub struct SimBus(Rc<RefCell<Device>>);
+
+/// Chip-select line of the simulated device
+pub struct SimCs(Rc<RefCell<Device>>);
+
+
+impl Lis3dshSim {
+  pub fn new() -> Self {
+    let mut registers = [0; 128];
+    // WHO_AM_I, INFO1, CTRL_REG4 and CTRL_REG6 reset values
+    registers[0x0F] = 0x3F;
+    registers[0x0D] = 0x21;
+    registers[0x20] = 0x07;
+    registers[CTRL_REG6] = 0x10;
+
+    Lis3dshSim(Rc::new(RefCell::new(Device {
+      registers,
+      selected: false,
+      command: None,
+      address: 0,
+      rx: None,
+      mosi: Vec::new(),
+      transactions: 0,
+    })))
+  }
+
+  pub fn split(&self) -> (SimBus, SimCs) {
+    (SimBus(self.0.clone()), SimCs(self.0.clone()))
+  }
+
+  pub fn register(&self, address: u8) -> u8 {
+    self.0.borrow().registers[usize::from(address)]
+  }
+
+  pub fn set_registers(&self, address: u8, values: &[u8]) {
+    let start = usize::from(address);
+    self.0.borrow_mut().registers[start..start + values.len()].copy_from_slice(values);
+  }
+
+  pub fn is_selected(&self) -> bool {
+    self.0.borrow().selected
+  }
+
+  /// Every byte the master has sent
+  pub fn mosi(&self) -> Vec<u8> {
+    self.0.borrow().mosi.clone()
+  }
+
+  /// Number of completed chip-select cycles
+  pub fn transactions(&self) -> usize {
+    self.0.borrow().transactions
+  }
+}
+
+impl Default for Lis3dshSim {
+  fn default() -> Self {
+    Lis3dshSim::new()
+  }
+}
+
+impl Device {
+  fn exchange(&mut self, byte: u8) -> u8 {
+    assert!(self.selected, "byte sent without chip-select");
+    self.mosi.push(byte);
+
+    let command = match self.command {
+      None => {
+        self.command = Some(byte);
+        self.address = byte & ADDRESS_MASK;
+        return GARBAGE;
+      }
+      Some(command) => command,
+    };
+
+    let address = usize::from(self.address);
+    let out = if command & READ_MASK != 0 {
+      self.registers[address]
+    } else {
+      self.registers[address] = byte;
+      GARBAGE
+    };
+
+    if self.registers[CTRL_REG6] & ADD_INC_MASK != 0 {
+      self.address = (self.address + 1) & ADDRESS_MASK;
+    }
+
+    out
+  }
+}
+
+impl FullDuplex<u8> for SimBus {
+  type Error = Infallible;
+
+  fn read(&mut self) -> nb::Result<u8, Self::Error> {
+    self.0.borrow_mut().rx.take().ok_or(nb::Error::WouldBlock)
+  }
+
+  fn send(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+    let mut device = self.0.borrow_mut();
+    let out = device.exchange(byte);
+    device.rx = Some(out);
+    Ok(())
+  }
+}
+
+impl OutputPin for SimCs {
+  type Error = Infallible;
+
+  fn set_low(&mut self) -> Result<(), Self::Error> {
+    let mut device = self.0.borrow_mut();
+    device.selected = true;
+    device.command = None;
+    Ok(())
+  }
+
+  fn set_high(&mut self) -> Result<(), Self::Error> {
+    let mut device = self.0.borrow_mut();
+    if device.selected {
+      device.transactions += 1;
+    }
+    device.selected = false;
+    Ok(())
+  }
+}
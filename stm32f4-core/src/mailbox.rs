@@ -0,0 +1,248 @@
+//! What happens to a message whose destination mailbox is full, and to
+//! scheduled messages that were cancelled on the way.
+
+/// Ties replies to requests and scheduled messages to their handles
+pub type CorrelationId = u16;
+
+/// Scheduled messages a mailbox keeps track of at a time
+pub const TIMER_COUNT: usize = 8;
+
+/// Chosen per destination in the mailbox registry
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overflow {
+  /// The message that does not fit is lost
+  DropNewest,
+  /// The oldest queued message is lost to make room
+  DropOldest,
+  /// Tried again after `delay_us`, lost after `attempts` retries
+  Retry { delay_us: u32, attempts: u8 },
+}
+
+/// What the router does with one message that did not fit
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Outcome {
+  Drop,
+  Displace,
+  RetryIn(u32),
+}
+
+/// Scheduled messages on their way to one mailbox. RTIC can't take a message
+/// back once it is scheduled, so a cancelled one is dropped when it arrives.
+#[derive(Debug)]
+pub struct Timers {
+  // ids with whether they have been cancelled. a plain array, the router's
+  // table of mailboxes is built in a `const`
+  pending: [Option<(CorrelationId, bool)>; TIMER_COUNT],
+}
+
+/// Router bookkeeping for one mailbox
+#[derive(Debug)]
+pub struct Mailbox<T> {
+  // RTIC gives no access to a queued message, so under `DropOldest` the newest
+  // one waits here and takes the place of the next one the task dequeues
+  displaced: Option<T>,
+  drops: u32,
+  pub timers: Timers,
+}
+
+
+impl Overflow {
+  /// `attempt` counts the retries the message has already had
+  pub fn resolve(self, attempt: u8) -> Outcome {
+    match self {
+      Overflow::DropNewest => Outcome::Drop,
+      Overflow::DropOldest => Outcome::Displace,
+      Overflow::Retry { delay_us, attempts } if attempt < attempts => Outcome::RetryIn(delay_us),
+      Overflow::Retry { .. } => Outcome::Drop,
+    }
+  }
+}
+
+impl Timers {
+  pub const fn new() -> Self {
+    Timers {
+      pending: [None; TIMER_COUNT],
+    }
+  }
+
+  /// Returns false when too many are pending, the message can't be cancelled then
+  pub fn add(&mut self, id: CorrelationId) -> bool {
+    match self.pending.iter_mut().find(|slot| slot.is_none()) {
+      Some(slot) => {
+        *slot = Some((id, false));
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Returns false when the message has already arrived or was never tracked
+  pub fn cancel(&mut self, id: CorrelationId) -> bool {
+    match self.pending.iter_mut().flatten().find(|(pending, _)| *pending == id) {
+      Some((_, cancelled)) => {
+        *cancelled = true;
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Called with every arriving message, `timer` is the id a scheduled one
+  /// was tracked under. Gives false for a cancelled one, anything else
+  /// arrives whatever its id.
+  pub fn admit(&mut self, timer: Option<CorrelationId>) -> bool {
+    match timer {
+      Some(id) => self.arrive(id),
+      None => true,
+    }
+  }
+
+  /// Gives false when the scheduled message with `id` was cancelled
+  pub fn arrive(&mut self, id: CorrelationId) -> bool {
+    match self.pending.iter_mut().find(|slot| matches!(slot, Some((pending, _)) if *pending == id)) {
+      Some(slot) => slot.take() != Some((id, true)),
+      None => true,
+    }
+  }
+
+  /// Forgets a message that could not be scheduled
+  pub fn remove(&mut self, id: CorrelationId) {
+    self.arrive(id);
+  }
+}
+
+impl Default for Timers {
+  fn default() -> Self {
+    Timers::new()
+  }
+}
+
+impl<T> Mailbox<T> {
+  pub const fn new() -> Self {
+    Mailbox {
+      displaced: None,
+      drops: 0,
+      timers: Timers::new(),
+    }
+  }
+
+  /// Parks the newest message until the task dequeues the next one. A message
+  /// already parked is lost.
+  pub fn displace(&mut self, newest: T) {
+    if self.displaced.replace(newest).is_some() {
+      self.drop_one();
+    }
+  }
+
+  /// Called with every dequeued message. A parked message takes its place, the
+  /// dequeued one is lost.
+  pub fn take_displaced(&mut self) -> Option<T> {
+    let newest = self.displaced.take();
+    if newest.is_some() {
+      self.drop_one();
+    }
+    newest
+  }
+
+  pub fn drop_one(&mut self) {
+    self.drops = self.drops.wrapping_add(1);
+  }
+
+  /// Messages lost since boot
+  pub fn drops(&self) -> u32 {
+    self.drops
+  }
+}
+
+impl<T> Default for Mailbox<T> {
+  fn default() -> Self {
+    Mailbox::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn retries_run_out() {
+    let retry = Overflow::Retry { delay_us: 100, attempts: 2 };
+
+    assert_eq!(retry.resolve(0), Outcome::RetryIn(100));
+    assert_eq!(retry.resolve(1), Outcome::RetryIn(100));
+    assert_eq!(retry.resolve(2), Outcome::Drop);
+  }
+
+  #[test]
+  fn displaced_message_replaces_the_next_dequeued() {
+    let mut mailbox = Mailbox::new();
+    assert_eq!(mailbox.take_displaced(), None);
+
+    mailbox.displace('a');
+    assert_eq!(mailbox.drops(), 0);
+
+    assert_eq!(mailbox.take_displaced(), Some('a'));
+    assert_eq!(mailbox.drops(), 1);
+    assert_eq!(mailbox.take_displaced(), None);
+  }
+
+  #[test]
+  fn only_the_newest_is_parked() {
+    let mut mailbox = Mailbox::new();
+    mailbox.displace('a');
+    mailbox.displace('b');
+
+    assert_eq!(mailbox.drops(), 1);
+    assert_eq!(mailbox.take_displaced(), Some('b'));
+    assert_eq!(mailbox.drops(), 2);
+  }
+
+  #[test]
+  fn cancelled_timers_do_not_arrive() {
+    let mut timers = Timers::new();
+    assert!(timers.add(1));
+    assert!(timers.add(2));
+
+    assert!(timers.cancel(1));
+    assert!(!timers.arrive(1));
+    assert!(timers.arrive(2));
+
+    // both are forgotten once they arrived
+    assert!(!timers.cancel(1));
+    assert!(!timers.cancel(2));
+  }
+
+  #[test]
+  fn only_scheduled_messages_are_matched_against_timers() {
+    let mut timers = Timers::new();
+    timers.add(5);
+    timers.cancel(5);
+
+    // a reply that happens to carry the id of the cancelled timer
+    assert!(timers.admit(None));
+    assert!(!timers.admit(Some(5)));
+  }
+
+  #[test]
+  fn untracked_messages_always_arrive() {
+    let mut timers = Timers::new();
+    while timers.add(7) {}
+
+    assert!(!timers.cancel(8));
+    assert!(timers.arrive(8));
+  }
+
+  #[test]
+  fn arrived_timers_make_room() {
+    let mut timers = Timers::new();
+    for id in 0..TIMER_COUNT as CorrelationId {
+      assert!(timers.add(id));
+    }
+    assert!(!timers.add(100));
+
+    assert!(timers.arrive(3));
+    assert!(timers.add(100));
+    assert!(timers.cancel(100));
+  }
+}
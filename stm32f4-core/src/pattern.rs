@@ -0,0 +1,210 @@
+//! Blink codes as data. A pattern is a list of steps, each of which holds a
+//! brightness for a while or plays a list of steps a number of times over.
+//! The pattern as a whole plays a number of times or forever:
+//!
+//! ```
+//! use stm32f4_core::pattern::{Pattern, Step, on, off, repeat};
+//!
+//! const SHORT: [Step; 2] = [on(150), off(150)];
+//! const CODE: Pattern = Pattern::forever(&[repeat(3, &SHORT), on(600), off(1_000)]);
+//! # assert_eq!(CODE.period_ms(), 2_500);
+//! ```
+//!
+//! Like the breathing, what a pattern shows is worked out from the time since
+//! it started, so it can be stepped at whatever pace suits the player.
+
+use crate::leds::FULL;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Step {
+  /// Holds `brightness` for `ms`
+  Hold { brightness: u16, ms: u32 },
+  /// Plays `steps` `times` over
+  Repeat { times: u8, steps: &'static [Step] },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Repeat {
+  Forever,
+  Times(u8),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Pattern {
+  pub steps: &'static [Step],
+  pub repeat: Repeat,
+}
+
+/// What a pattern shows at some point and for how much longer
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Level {
+  pub brightness: u16,
+  pub remaining_ms: u32,
+}
+
+
+pub const fn on(ms: u32) -> Step {
+  Step::Hold { brightness: FULL, ms }
+}
+
+pub const fn off(ms: u32) -> Step {
+  Step::Hold { brightness: 0, ms }
+}
+
+pub const fn dim(brightness: u16, ms: u32) -> Step {
+  Step::Hold { brightness, ms }
+}
+
+pub const fn repeat(times: u8, steps: &'static [Step]) -> Step {
+  Step::Repeat { times, steps }
+}
+
+// one pass through `steps`, saturates rather than wrap
+const fn duration(steps: &[Step]) -> u32 {
+  let mut total: u32 = 0;
+  let mut i = 0;
+  while i < steps.len() {
+    let ms = match steps[i] {
+      Step::Hold { ms, .. } => ms,
+      Step::Repeat { times, steps } => duration(steps).saturating_mul(times as u32),
+    };
+    total = total.saturating_add(ms);
+    i += 1;
+  }
+  total
+}
+
+fn level(steps: &[Step], mut elapsed_ms: u32) -> Option<Level> {
+  for step in steps {
+    match *step {
+      Step::Hold { brightness, ms } => {
+        if elapsed_ms < ms {
+          return Some(Level { brightness, remaining_ms: ms - elapsed_ms });
+        }
+        elapsed_ms -= ms;
+      }
+      Step::Repeat { times, steps } => {
+        let pass = duration(steps);
+        let all = pass.saturating_mul(times as u32);
+        if elapsed_ms < all {
+          return level(steps, elapsed_ms % pass);
+        }
+        elapsed_ms -= all;
+      }
+    }
+  }
+  None
+}
+
+impl Pattern {
+  pub const fn forever(steps: &'static [Step]) -> Self {
+    Pattern { steps, repeat: Repeat::Forever }
+  }
+
+  pub const fn times(times: u8, steps: &'static [Step]) -> Self {
+    Pattern { steps, repeat: Repeat::Times(times) }
+  }
+
+  /// One pass through the steps
+  pub const fn period_ms(&self) -> u32 {
+    duration(self.steps)
+  }
+
+  /// `None` once the pattern has played out. An empty one never plays.
+  pub fn at(&self, elapsed_ms: u32) -> Option<Level> {
+    let period = self.period_ms();
+    if period == 0 {
+      return None;
+    }
+
+    match self.repeat {
+      Repeat::Forever => level(self.steps, elapsed_ms % period),
+      Repeat::Times(times) if elapsed_ms < period.saturating_mul(times as u32) => {
+        level(self.steps, elapsed_ms % period)
+      }
+      Repeat::Times(_) => None,
+    }
+  }
+
+  /// Keeps the time into a pattern that plays forever from growing without
+  /// bound, the rest end on their own
+  pub fn wrap(&self, elapsed_ms: u32) -> u32 {
+    match self.repeat {
+      Repeat::Forever => elapsed_ms.checked_rem(self.period_ms()).unwrap_or(0),
+      Repeat::Times(_) => elapsed_ms,
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SHORT: [Step; 2] = [on(100), off(100)];
+  const THREE_SHORT_ONE_LONG: Pattern = Pattern::forever(&[repeat(3, &SHORT), on(300), off(300)]);
+
+  // one character per 100ms, '#' for full, '+' for dimmed, '.' for dark and
+  // nothing once the pattern has played out
+  fn timeline(pattern: &Pattern, until_ms: u32) -> String {
+    (0..until_ms).step_by(100).filter_map(|t| pattern.at(t)).map(|level| match level.brightness {
+      0 => '.',
+      FULL => '#',
+      _ => '+',
+    }).collect()
+  }
+
+  #[test]
+  fn three_short_one_long() {
+    assert_eq!(THREE_SHORT_ONE_LONG.period_ms(), 1_200);
+    assert_eq!(timeline(&THREE_SHORT_ONE_LONG, 2_400), "#.#.#.###...#.#.#.###...");
+  }
+
+  #[test]
+  fn counted_patterns_play_out() {
+    const TWICE: Pattern = Pattern::times(2, &[on(200), dim(400, 100)]);
+
+    assert_eq!(timeline(&TWICE, 2_000), "##+##+");
+    assert_eq!(TWICE.at(600), None);
+  }
+
+  #[test]
+  fn repeats_nest() {
+    const BLIP: [Step; 2] = [on(100), off(100)];
+    const PAIR: [Step; 2] = [repeat(2, &BLIP), off(200)];
+    const PAIRS: Pattern = Pattern::times(1, &[repeat(2, &PAIR)]);
+
+    assert_eq!(timeline(&PAIRS, 2_000), "#.#...#.#...");
+  }
+
+  #[test]
+  fn levels_know_how_long_they_hold() {
+    assert_eq!(THREE_SHORT_ONE_LONG.at(650), Some(Level { brightness: FULL, remaining_ms: 250 }));
+    assert_eq!(THREE_SHORT_ONE_LONG.at(1_250), Some(Level { brightness: FULL, remaining_ms: 50 }));
+  }
+
+  #[test]
+  fn empty_patterns_never_play() {
+    const NEVER: Pattern = Pattern::forever(&[repeat(0, &SHORT)]);
+
+    assert_eq!(Pattern::forever(&[]).at(0), None);
+    assert_eq!(NEVER.at(0), None);
+    assert_eq!(Pattern::times(0, &SHORT).at(0), None);
+    assert_eq!(Pattern::forever(&[]).wrap(500), 0);
+  }
+
+  #[test]
+  fn endless_patterns_wrap() {
+    assert_eq!(THREE_SHORT_ONE_LONG.wrap(2_450), 50);
+    assert_eq!(Pattern::times(3, &SHORT).wrap(450), 450);
+  }
+
+  #[test]
+  fn durations_saturate() {
+    const LONG: [Step; 1] = [on(u32::MAX / 2)];
+    const LONGER: Pattern = Pattern::forever(&[repeat(3, &LONG)]);
+
+    assert_eq!(LONGER.period_ms(), u32::MAX);
+    assert_eq!(LONGER.at(u32::MAX - 1).map(|l| l.brightness), Some(FULL));
+  }
+}
@@ -0,0 +1,133 @@
+use crate::spi_drv::TransferMode;
+
+/// Transactions run in one mode before the driver switches to the other
+pub const WINDOW: u32 = 20;
+
+/// CPU time spent driving the bus in one transfer mode
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+  pub transactions: u32,
+  pub cycles: u32,
+}
+
+/// Running totals for both transfer modes so they can be compared on the target
+#[derive(Debug)]
+pub struct Benchmark {
+  pub interrupt: Usage,
+  pub dma: Usage,
+  // the modes take turns a window each until one is set explicitly
+  pub alternate: bool,
+}
+
+
+impl Usage {
+  pub fn add(&mut self, cycles: u32) {
+    self.cycles = self.cycles.saturating_add(cycles);
+  }
+
+  pub fn finish(&mut self) {
+    self.transactions = self.transactions.wrapping_add(1);
+  }
+
+  pub fn cycles_per_transaction(&self) -> Option<u32> {
+    self.cycles.checked_div(self.transactions)
+  }
+}
+
+impl Benchmark {
+  pub const fn new() -> Self {
+    Benchmark {
+      interrupt: Usage { transactions: 0, cycles: 0 },
+      dma: Usage { transactions: 0, cycles: 0 },
+      alternate: true,
+    }
+  }
+
+  /// Closes a transaction run in `mode`, gives the mode for the next one
+  pub fn finish(&mut self, mode: TransferMode) -> TransferMode {
+    let usage = self.get_mut(mode);
+    usage.finish();
+    let window_done = usage.transactions.is_multiple_of(WINDOW);
+
+    match mode {
+      _ if !self.alternate || !window_done => mode,
+      TransferMode::Interrupt => TransferMode::Dma,
+      TransferMode::Dma => TransferMode::Interrupt,
+    }
+  }
+
+  pub fn get_mut(&mut self, mode: TransferMode) -> &mut Usage {
+    match mode {
+      TransferMode::Interrupt => &mut self.interrupt,
+      TransferMode::Dma => &mut self.dma,
+    }
+  }
+}
+
+impl Default for Benchmark {
+  fn default() -> Self {
+    Benchmark::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn modes_are_counted_separately() {
+    let mut benchmark = Benchmark::default();
+
+    for _ in 0..7 {
+      benchmark.get_mut(TransferMode::Interrupt).add(300);
+    }
+    benchmark.get_mut(TransferMode::Interrupt).finish();
+    benchmark.get_mut(TransferMode::Dma).add(500);
+    benchmark.get_mut(TransferMode::Dma).finish();
+
+    assert_eq!(benchmark.interrupt.cycles_per_transaction(), Some(2100));
+    assert_eq!(benchmark.dma.cycles_per_transaction(), Some(500));
+  }
+
+  #[test]
+  fn modes_take_turns_a_window_each() {
+    let mut benchmark = Benchmark::new();
+    let mut mode = TransferMode::Interrupt;
+
+    for _ in 1..WINDOW {
+      mode = benchmark.finish(mode);
+      assert_eq!(mode, TransferMode::Interrupt);
+    }
+    mode = benchmark.finish(mode);
+    assert_eq!(mode, TransferMode::Dma);
+
+    for _ in 0..WINDOW {
+      mode = benchmark.finish(mode);
+    }
+    assert_eq!(mode, TransferMode::Interrupt);
+    assert_eq!((benchmark.interrupt.transactions, benchmark.dma.transactions), (WINDOW, WINDOW));
+  }
+
+  #[test]
+  fn a_set_mode_stays() {
+    let mut benchmark = Benchmark { alternate: false, ..Benchmark::new() };
+
+    for _ in 0..WINDOW {
+      assert_eq!(benchmark.finish(TransferMode::Dma), TransferMode::Dma);
+    }
+  }
+
+  #[test]
+  fn no_transactions_no_average() {
+    assert_eq!(Usage::default().cycles_per_transaction(), None);
+  }
+
+  #[test]
+  fn cycles_saturate() {
+    let mut usage = Usage::default();
+    usage.add(u32::MAX);
+    usage.add(1);
+    assert_eq!(usage.cycles, u32::MAX);
+  }
+}
@@ -0,0 +1,76 @@
+// every transaction gets a fixed allowance plus some time per byte. the bus
+// itself needs 8us per byte at 1MHz, the rest covers the scheduling between
+// bytes in interrupt mode
+const MIN_TIMEOUT_US: u32 = 5_000;
+const PER_BYTE_US: u32 = 500;
+
+/// How long a transaction of `bytes` bytes may hold the bus
+pub fn timeout_us(bytes: usize) -> u32 {
+  MIN_TIMEOUT_US.saturating_add(PER_BYTE_US.saturating_mul(bytes as u32))
+}
+
+/// Tells the timeout of the transaction on the bus apart from the timeouts of
+/// transactions that have finished in the meantime
+#[derive(Debug)]
+pub struct Deadline {
+  generation: u32,
+  armed: bool,
+}
+
+
+impl Deadline {
+  pub const fn new() -> Self {
+    Deadline {
+      generation: 0,
+      armed: false,
+    }
+  }
+
+  /// Returns the generation the scheduled timeout has to carry
+  pub fn arm(&mut self) -> u32 {
+    self.generation = self.generation.wrapping_add(1);
+    self.armed = true;
+    self.generation
+  }
+
+  pub fn disarm(&mut self) {
+    self.armed = false;
+  }
+
+  pub fn is_current(&self, generation: u32) -> bool {
+    self.armed && generation == self.generation
+  }
+}
+
+impl Default for Deadline {
+  fn default() -> Self {
+    Deadline::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn timeouts_grow_with_the_transfer() {
+    assert_eq!(timeout_us(0), 5_000);
+    assert_eq!(timeout_us(7), 8_500);
+    assert!(timeout_us(usize::MAX) > timeout_us(33));
+  }
+
+  #[test]
+  fn only_the_latest_armed_deadline_is_current() {
+    let mut deadline = Deadline::default();
+    let first = deadline.arm();
+    assert!(deadline.is_current(first));
+
+    let second = deadline.arm();
+    assert!(!deadline.is_current(first));
+    assert!(deadline.is_current(second));
+
+    deadline.disarm();
+    assert!(!deadline.is_current(second));
+  }
+}
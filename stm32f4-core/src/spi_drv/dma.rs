@@ -0,0 +1,165 @@
+use crate::spi_drv::{
+  Read,
+  Write,
+  RX_BUFFER_SIZE,
+  TX_BUFFER_SIZE,
+};
+
+/// Bytes on the wire for the longest transaction, the command byte included
+pub const FRAME_SIZE: usize = RX_BUFFER_SIZE + 1;
+
+/// A whole transaction laid out for the DMA streams. The TX stream clocks out
+/// `tx` while the RX stream fills `rx`, both `len` bytes long.
+pub struct Frame {
+  tx: [u8; FRAME_SIZE],
+  rx: [u8; FRAME_SIZE],
+  len: usize,
+  reading: bool,
+}
+
+
+impl Frame {
+  pub const fn new() -> Self {
+    Frame {
+      tx: [0; FRAME_SIZE],
+      rx: [0; FRAME_SIZE],
+      len: 0,
+      reading: false,
+    }
+  }
+
+  /// Returns the number of bytes both streams have to move
+  pub fn load_read(&mut self, r: Read) -> usize {
+    let len = usize::from(r.len).min(RX_BUFFER_SIZE) + 1;
+    self.tx[0] = r.reg;
+    // dummy bytes to keep the clock running
+    for b in self.tx[1..len].iter_mut() {
+      *b = 0x00;
+    }
+
+    self.len = len;
+    self.reading = true;
+    len
+  }
+
+  /// Returns the number of bytes both streams have to move
+  pub fn load_write(&mut self, w: Write) -> usize {
+    // the length includes the register byte
+    let len = usize::from(w.len).clamp(1, TX_BUFFER_SIZE);
+    self.tx[0] = w.reg;
+    self.tx[1..len].copy_from_slice(&w.data[..len - 1]);
+
+    self.len = len;
+    self.reading = false;
+    len
+  }
+
+  pub fn tx_ptr(&self) -> *const u8 {
+    self.tx.as_ptr()
+  }
+
+  pub fn rx_ptr(&mut self) -> *mut u8 {
+    self.rx.as_mut_ptr()
+  }
+
+  pub fn tx(&self) -> &[u8] {
+    &self.tx[..self.len]
+  }
+
+  /// Buffer the RX stream writes into, exposed for stepping through a frame without DMA
+  pub fn rx_mut(&mut self) -> &mut [u8] {
+    &mut self.rx[..self.len]
+  }
+
+  /// Data of the last read without the garbage byte clocked in with the command
+  pub fn received(&self) -> &[u8] {
+    if self.reading {
+      &self.rx[1..self.len]
+    } else {
+      &[]
+    }
+  }
+}
+
+impl Default for Frame {
+  fn default() -> Self {
+    Frame::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use embedded_hal::{
+    spi::FullDuplex,
+    digital::v2::OutputPin,
+  };
+  use crate::lis3dsh::sim::Lis3dshSim;
+  use crate::spi_drv::Device;
+
+  // does what the two streams do, one byte out and one byte in at a time
+  fn run(frame: &mut Frame, sim: &Lis3dshSim) {
+    let (mut bus, mut cs) = sim.split();
+    let tx = frame.tx().to_vec();
+
+    cs.set_low().unwrap();
+    for (out, slot) in tx.iter().zip(frame.rx_mut().iter_mut()) {
+      bus.send(*out).unwrap();
+      *slot = bus.read().unwrap();
+    }
+    cs.set_high().unwrap();
+  }
+
+  #[test]
+  fn read_frame_has_a_dummy_byte_per_data_byte() {
+    let sim = Lis3dshSim::new();
+    sim.set_registers(0x28, &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+    let mut frame = Frame::new();
+
+    let len = frame.load_read(Read { device: Device::Lis3dsh, reg: 0xA8, len: 6 });
+    assert_eq!(len, 7);
+    run(&mut frame, &sim);
+
+    assert_eq!(sim.mosi(), vec![0xA8, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(frame.received(), &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+  }
+
+  #[test]
+  fn write_frame_carries_the_data() {
+    let sim = Lis3dshSim::new();
+    let mut frame = Frame::new();
+    let mut data = [0; TX_BUFFER_SIZE];
+    data[..2].copy_from_slice(&[0x11, 0x22]);
+
+    let len = frame.load_write(Write { device: Device::Lis3dsh, reg: 0x10, len: 3, data });
+    assert_eq!(len, 3);
+    run(&mut frame, &sim);
+
+    assert_eq!(sim.register(0x10), 0x11);
+    assert_eq!(sim.register(0x11), 0x22);
+    assert!(frame.received().is_empty());
+  }
+
+  #[test]
+  fn reads_are_clamped_to_the_frame() {
+    let mut frame = Frame::new();
+
+    let len = frame.load_read(Read { device: Device::Lis3dsh, reg: 0x80, len: 200 });
+
+    assert_eq!(len, FRAME_SIZE);
+    assert_eq!(frame.received().len(), RX_BUFFER_SIZE);
+  }
+
+  #[test]
+  fn a_short_read_after_a_long_one_sends_only_zeros() {
+    let mut frame = Frame::new();
+    let mut data = [0xAA; TX_BUFFER_SIZE];
+    data[0] = 0x55;
+
+    frame.load_write(Write { device: Device::Lis3dsh, reg: 0x10, len: TX_BUFFER_SIZE as u8, data });
+    frame.load_read(Read { device: Device::Lis3dsh, reg: 0x8F, len: 4 });
+
+    assert_eq!(frame.tx(), &[0x8F, 0, 0, 0, 0]);
+  }
+}
@@ -0,0 +1,61 @@
+use crate::spi_drv::Error;
+
+/// Bus faults seen since start-up
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ErrorCounters {
+  pub overrun: u32,
+  pub mode_fault: u32,
+  pub crc: u32,
+  pub timeout: u32,
+  pub dma: u32,
+}
+
+
+impl ErrorCounters {
+  /// Rejections and cancellations are not bus faults and are not counted
+  pub fn record(&mut self, e: Error) {
+    let counter = match e {
+      Error::Overrun => &mut self.overrun,
+      Error::ModeFault => &mut self.mode_fault,
+      Error::Crc => &mut self.crc,
+      Error::Timeout => &mut self.timeout,
+      Error::Dma => &mut self.dma,
+      Error::Rejected | Error::Cancelled => return,
+    };
+
+    *counter = counter.wrapping_add(1);
+  }
+
+  pub fn total(&self) -> u32 {
+    self.overrun.wrapping_add(self.mode_fault).wrapping_add(self.crc).wrapping_add(self.timeout).wrapping_add(self.dma)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn faults_are_counted_by_kind() {
+    let mut counters = ErrorCounters::default();
+    counters.record(Error::Overrun);
+    counters.record(Error::Overrun);
+    counters.record(Error::ModeFault);
+    counters.record(Error::Crc);
+    counters.record(Error::Timeout);
+    counters.record(Error::Dma);
+
+    assert_eq!(counters, ErrorCounters { overrun: 2, mode_fault: 1, crc: 1, timeout: 1, dma: 1 });
+    assert_eq!(counters.total(), 6);
+  }
+
+  #[test]
+  fn rejections_are_not_faults() {
+    let mut counters = ErrorCounters::default();
+    counters.record(Error::Rejected);
+    counters.record(Error::Cancelled);
+
+    assert_eq!(counters.total(), 0);
+  }
+}
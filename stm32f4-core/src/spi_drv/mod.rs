@@ -0,0 +1,224 @@
+use embedded_hal::spi::{Mode, MODE_0};
+use heapless::{
+  Vec,
+  consts::U32,
+};
+
+pub mod spi1;
+pub mod queue;
+pub mod transfer;
+pub mod dma;
+pub mod benchmark;
+pub mod errors;
+pub mod deadline;
+
+// received bytes are copied into the completion message, see `Bytes`
+pub const RX_BUFFER_SIZE: usize = 32;
+pub const TX_BUFFER_SIZE: usize = 10;
+
+/// Picked by the client, handed back unchanged with the completion
+pub type TransactionId = u16;
+
+/// Payload of a finished read, holds up to `RX_BUFFER_SIZE` bytes
+pub type Bytes = Vec<u8, U32>;
+
+/// Number of chip-select lines on the bus, one per `Device`
+pub const DEVICE_COUNT: usize = 1;
+
+/// How the bytes of a transaction are moved
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferMode {
+  // one RXNE interrupt per byte
+  Interrupt,
+  // both directions handled by DMA streams, one interrupt at the end
+  Dma,
+}
+
+/// Devices hanging off the bus
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Device {
+  Lis3dsh,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Read {
+  pub device: Device,
+  pub reg: u8,
+  pub len: u8
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Write {
+  pub device: Device,
+  pub reg: u8,
+  pub len: u8,
+  pub data: [u8; TX_BUFFER_SIZE]
+}
+
+/// A transaction waiting for the bus
+#[derive(Debug, Clone, Copy)]
+pub enum Request {
+  Read(TransactionId, Read),
+  Write(TransactionId, Write),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Error {
+  // the bus could not take the request
+  Rejected,
+  // a byte came in before the previous one was read
+  Overrun,
+  // another master pulled NSS low
+  ModeFault,
+  Crc,
+  // the transaction held the bus for longer than its deadline
+  Timeout,
+  // a DMA stream flagged a transfer, FIFO or direct mode error
+  Dma,
+  // a client cancelled the transaction while it was on the bus
+  Cancelled,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Response {
+  Read(Bytes),
+  Written,
+}
+
+/// Outcome of a transaction, sent to the task that started it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Completion {
+  pub id: TransactionId,
+  pub device: Device,
+  pub result: Result<Response, Error>,
+}
+
+#[derive(Debug)]
+pub enum Message {
+  Ignore,
+  StartRead(TransactionId, Read),
+  StartWrite(TransactionId, Write),
+  TxEvent,
+  RxEvent,
+  // raised by the interrupt handler, the flags are cleared by then
+  Error(Error),
+  DmaComplete,
+  // carries the generation of the deadline that scheduled it
+  Timeout(u32),
+  FinishTransaction,
+  CancelTransaction,
+  // only taken while idling, applies to the transactions that follow and
+  // stops the modes from taking turns
+  SetTransferMode(TransferMode),
+  ReportUsage,
+  // the sender gets the error counters back
+  ReadErrors,
+}
+
+#[derive(Debug)]
+pub enum Action {
+  DoNothing,
+  Reset,
+  Reject,
+  Enqueue(Request),
+  StartNext,
+  StartRead(TransactionId, Read),
+  StartWrite(TransactionId, Write),
+  ContinueRead,
+  ContinueWrite,
+  FinishRead,
+  FinishWrite,
+  SetTransferMode(TransferMode),
+  ReportUsage,
+  Abort(Error),
+  CountError(Error),
+  ReportErrors,
+}
+
+
+impl Device {
+  /// Index of the device's chip-select line
+  pub fn index(self) -> usize {
+    match self {
+      Device::Lis3dsh => 0,
+    }
+  }
+
+  pub fn mode(self) -> Mode {
+    match self {
+      Device::Lis3dsh => MODE_0,
+    }
+  }
+}
+
+impl Request {
+  pub fn id(&self) -> TransactionId {
+    match self {
+      Request::Read(id, _) | Request::Write(id, _) => *id,
+    }
+  }
+
+  pub fn device(&self) -> Device {
+    match self {
+      Request::Read(_, r) => r.device,
+      Request::Write(_, w) => w.device,
+    }
+  }
+
+  /// Bytes on the wire, the register byte included
+  pub fn bytes(&self) -> usize {
+    match self {
+      Request::Read(_, r) => usize::from(r.len) + 1,
+      Request::Write(_, w) => usize::from(w.len),
+    }
+  }
+}
+
+impl Completion {
+  pub fn rejected(request: &Request) -> Self {
+    Completion {
+      id: request.id(),
+      device: request.device(),
+      result: Err(Error::Rejected),
+    }
+  }
+
+  /// What came of the transaction, for the trace
+  pub fn name(&self) -> &'static str {
+    match self.result {
+      Ok(Response::Read(_)) => "Read",
+      Ok(Response::Written) => "Written",
+      Err(_) => "Failed",
+    }
+  }
+}
+
+impl Message {
+  /// Variant name, for the trace
+  pub fn name(&self) -> &'static str {
+    match self {
+      Message::Ignore => "Ignore",
+      Message::StartRead(..) => "StartRead",
+      Message::StartWrite(..) => "StartWrite",
+      Message::TxEvent => "TxEvent",
+      Message::RxEvent => "RxEvent",
+      Message::Error(_) => "Error",
+      Message::DmaComplete => "DmaComplete",
+      Message::Timeout(_) => "Timeout",
+      Message::FinishTransaction => "FinishTransaction",
+      Message::CancelTransaction => "CancelTransaction",
+      Message::SetTransferMode(_) => "SetTransferMode",
+      Message::ReportUsage => "ReportUsage",
+      Message::ReadErrors => "ReadErrors",
+    }
+  }
+}
+
+impl From<Request> for Message {
+  fn from(request: Request) -> Message {
+    match request {
+      Request::Read(id, r) => Message::StartRead(id, r),
+      Request::Write(id, w) => Message::StartWrite(id, w),
+    }
+  }
+}
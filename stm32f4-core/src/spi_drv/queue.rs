@@ -0,0 +1,95 @@
+use heapless::{
+  spsc,
+  consts::U8,
+};
+
+use crate::spi_drv::Request;
+
+/// A request together with the task that has to be told about its outcome
+#[derive(Debug, Clone, Copy)]
+pub struct Pending<O> {
+  pub origin: O,
+  pub request: Request,
+}
+
+/// Requests that arrived while the bus was busy, served first come first served
+pub struct Queue<O> {
+  pending: spsc::Queue<Pending<O>, U8>,
+}
+
+
+impl<O> Queue<O> {
+  pub fn new() -> Self {
+    Queue {
+      pending: spsc::Queue::new(),
+    }
+  }
+
+  /// Hands the request back when the queue is full
+  pub fn push(&mut self, origin: O, request: Request) -> Result<(), Pending<O>> {
+    self.pending.enqueue(Pending { origin, request })
+  }
+
+  pub fn pop(&mut self) -> Option<Pending<O>> {
+    self.pending.dequeue()
+  }
+
+  pub fn len(&self) -> usize {
+    self.pending.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty()
+  }
+}
+
+impl<O> Default for Queue<O> {
+  fn default() -> Self {
+    Queue::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spi_drv::{Device, Read};
+
+  fn read(reg: u8) -> Request {
+    Request::Read(u16::from(reg), Read { device: Device::Lis3dsh, reg, len: 1 })
+  }
+
+  #[test]
+  fn requests_come_out_in_arrival_order() {
+    let mut queue = Queue::new();
+    queue.push('a', read(0x8F)).unwrap();
+    queue.push('b', read(0x8D)).unwrap();
+
+    let first = queue.pop().unwrap();
+    assert_eq!(first.origin, 'a');
+    assert!(matches!(first.request, Request::Read(0x8F, Read { reg: 0x8F, .. })));
+
+    let second = queue.pop().unwrap();
+    assert_eq!(second.origin, 'b');
+    assert!(matches!(second.request, Request::Read(0x8D, Read { reg: 0x8D, .. })));
+
+    assert!(queue.pop().is_none());
+  }
+
+  #[test]
+  fn full_queue_hands_the_request_back() {
+    let mut queue = Queue::new();
+    let mut accepted = 0;
+    while queue.push(accepted, read(0x8F)).is_ok() {
+      accepted += 1;
+    }
+
+    let rejected = queue.push(99, read(0x8D)).unwrap_err();
+    assert_eq!(rejected.origin, 99);
+    assert_eq!(queue.len(), accepted);
+
+    // draining makes room again
+    queue.pop().unwrap();
+    assert!(queue.push(99, read(0x8D)).is_ok());
+  }
+}
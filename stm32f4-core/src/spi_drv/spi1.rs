@@ -0,0 +1,254 @@
+use crate::spi_drv::{
+  Message,
+  Action,
+  Request,
+  Error,
+  deadline::Deadline,
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum State {
+  Idling,
+  Reading,
+  Writing,
+}
+
+
+impl State {
+  pub fn next(self, msg: &Message, deadline: &Deadline) -> (State, Action) {
+    match (self, msg) {
+      (State::Idling, Message::StartRead(id, r)) => {
+        (State::Reading, Action::StartRead(*id, *r))
+      }
+      (State::Idling, Message::StartWrite(id, w)) => {
+        (State::Writing, Action::StartWrite(*id, *w))
+      }
+      (State::Reading, Message::RxEvent) => {
+        (State::Reading, Action::ContinueRead)
+      }
+      (State::Writing, Message::RxEvent) => {
+        (State::Writing, Action::ContinueWrite)
+      }
+      (State::Reading, Message::DmaComplete) => {
+        (State::Reading, Action::FinishRead)
+      }
+      (State::Writing, Message::DmaComplete) => {
+        (State::Writing, Action::FinishWrite)
+      }
+      // the result goes out with the data, the bus is free right away
+      (State::Reading, Message::FinishTransaction) => {
+        (State::Idling, Action::StartNext)
+      }
+      (State::Writing, Message::FinishTransaction) => {
+        (State::Idling, Action::StartNext)
+      }
+      // the bus is busy, the request has to wait for its turn
+      (s, Message::StartRead(id, r)) => {
+        (s, Action::Enqueue(Request::Read(*id, *r)))
+      }
+      (s, Message::StartWrite(id, w)) => {
+        (s, Action::Enqueue(Request::Write(*id, *w)))
+      }
+      // the transfer is lost, the originator hears about it and the bus moves on
+      (State::Reading, Message::Error(e)) |
+      (State::Writing, Message::Error(e)) => {
+        (State::Idling, Action::Abort(*e))
+      }
+      (State::Idling, Message::Error(e)) => {
+        (State::Idling, Action::CountError(*e))
+      }
+      (State::Reading, Message::Timeout(generation)) |
+      (State::Writing, Message::Timeout(generation)) if deadline.is_current(*generation) => {
+        (State::Idling, Action::Abort(Error::Timeout))
+      }
+      // left over from a transaction that has finished since
+      (s, Message::Timeout(_)) => {
+        (s, Action::DoNothing)
+      }
+      // the originator is told like for any other aborted transfer
+      (State::Reading, Message::CancelTransaction) |
+      (State::Writing, Message::CancelTransaction) => {
+        (State::Idling, Action::Abort(Error::Cancelled))
+      }
+      (State::Idling, Message::CancelTransaction) => {
+        (State::Idling, Action::Reset)
+      }
+      (State::Idling, Message::SetTransferMode(mode)) => {
+        (State::Idling, Action::SetTransferMode(*mode))
+      }
+      (s, Message::ReportUsage) => {
+        (s, Action::ReportUsage)
+      }
+      (s, Message::ReadErrors) => {
+        (s, Action::ReportErrors)
+      }
+      (s, _m) => {
+        (s, Action::Reject)
+      }
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spi_drv::{Device, Read, Write, TransferMode, TX_BUFFER_SIZE};
+
+  const READ: Read = Read { device: Device::Lis3dsh, reg: 0x8F, len: 1 };
+  const WRITE: Write = Write { device: Device::Lis3dsh, reg: 0x20, len: 2, data: [0; TX_BUFFER_SIZE] };
+  // no timeout pending
+  const IDLE: Deadline = Deadline::new();
+
+  #[test]
+  fn read_runs_until_finished() {
+    let (state, action) = State::Idling.next(&Message::StartRead(7, READ), &IDLE);
+    assert_eq!(state, State::Reading);
+    assert!(matches!(action, Action::StartRead(7, Read { reg: 0x8F, len: 1, .. })));
+
+    let (state, action) = state.next(&Message::RxEvent, &IDLE);
+    assert_eq!(state, State::Reading);
+    assert!(matches!(action, Action::ContinueRead));
+
+    let (state, action) = state.next(&Message::FinishTransaction, &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::StartNext));
+  }
+
+  #[test]
+  fn write_runs_until_finished() {
+    let (state, action) = State::Idling.next(&Message::StartWrite(8, WRITE), &IDLE);
+    assert_eq!(state, State::Writing);
+    assert!(matches!(action, Action::StartWrite(8, Write { reg: 0x20, len: 2, .. })));
+
+    let (state, action) = state.next(&Message::RxEvent, &IDLE);
+    assert_eq!(state, State::Writing);
+    assert!(matches!(action, Action::ContinueWrite));
+
+    let (state, action) = state.next(&Message::FinishTransaction, &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::StartNext));
+  }
+
+  #[test]
+  fn requests_while_busy_are_queued() {
+    for busy in [State::Reading, State::Writing].iter() {
+      let (state, action) = busy.next(&Message::StartRead(1, READ), &IDLE);
+      assert_eq!(state, *busy);
+      assert!(matches!(action, Action::Enqueue(Request::Read(1, Read { reg: 0x8F, .. }))));
+
+      let (state, action) = busy.next(&Message::StartWrite(2, WRITE), &IDLE);
+      assert_eq!(state, *busy);
+      assert!(matches!(action, Action::Enqueue(Request::Write(2, Write { reg: 0x20, .. }))));
+    }
+  }
+
+  #[test]
+  fn queued_request_starts_from_idle() {
+    let (state, _) = State::Idling.next(&Message::StartRead(1, READ), &IDLE);
+    let (state, action) = state.next(&Message::FinishTransaction, &IDLE);
+    assert!(matches!(action, Action::StartNext));
+
+    // the firmware replays the dequeued request
+    let (state, action) = state.next(&Request::Write(2, WRITE).into(), &IDLE);
+    assert_eq!(state, State::Writing);
+    assert!(matches!(action, Action::StartWrite(2, Write { reg: 0x20, .. })));
+  }
+
+  #[test]
+  fn unexpected_events_are_rejected() {
+    let (state, action) = State::Idling.next(&Message::RxEvent, &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::Reject));
+
+    let (state, action) = State::Idling.next(&Message::FinishTransaction, &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::Reject));
+  }
+
+  #[test]
+  fn dma_completion_finishes_the_transfer() {
+    let (state, _) = State::Idling.next(&Message::StartRead(1, READ), &IDLE);
+    assert!(matches!(state.next(&Message::DmaComplete, &IDLE), (State::Reading, Action::FinishRead)));
+
+    let (state, _) = State::Idling.next(&Message::StartWrite(2, WRITE), &IDLE);
+    assert!(matches!(state.next(&Message::DmaComplete, &IDLE), (State::Writing, Action::FinishWrite)));
+
+    assert!(matches!(State::Idling.next(&Message::DmaComplete, &IDLE), (State::Idling, Action::Reject)));
+  }
+
+  #[test]
+  fn transfer_mode_only_changes_between_transactions() {
+    let (state, action) = State::Idling.next(&Message::SetTransferMode(TransferMode::Dma), &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::SetTransferMode(TransferMode::Dma)));
+
+    let (state, action) = State::Reading.next(&Message::SetTransferMode(TransferMode::Dma), &IDLE);
+    assert_eq!(state, State::Reading);
+    assert!(matches!(action, Action::Reject));
+  }
+
+  #[test]
+  fn bus_errors_abort_the_transfer() {
+    for busy in [State::Reading, State::Writing].iter() {
+      let (state, action) = busy.next(&Message::Error(Error::Overrun), &IDLE);
+      assert_eq!(state, State::Idling);
+      assert!(matches!(action, Action::Abort(Error::Overrun)));
+    }
+
+    // nothing to abort, the error still gets counted
+    let (state, action) = State::Idling.next(&Message::Error(Error::ModeFault), &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::CountError(Error::ModeFault)));
+  }
+
+  #[test]
+  fn error_counters_can_be_read_at_any_time() {
+    for s in [State::Idling, State::Reading, State::Writing].iter() {
+      assert!(matches!(s.next(&Message::ReadErrors, &IDLE), (_, Action::ReportErrors)));
+    }
+  }
+
+  #[test]
+  fn current_timeout_aborts_the_transfer() {
+    let mut deadline = Deadline::default();
+    let generation = deadline.arm();
+
+    for busy in [State::Reading, State::Writing].iter() {
+      let (state, action) = busy.next(&Message::Timeout(generation), &deadline);
+      assert_eq!(state, State::Idling);
+      assert!(matches!(action, Action::Abort(Error::Timeout)));
+    }
+  }
+
+  #[test]
+  fn stale_timeouts_are_ignored() {
+    let mut deadline = Deadline::default();
+    let stale = deadline.arm();
+    let current = deadline.arm();
+
+    let (state, action) = State::Reading.next(&Message::Timeout(stale), &deadline);
+    assert_eq!(state, State::Reading);
+    assert!(matches!(action, Action::DoNothing));
+
+    // the transaction finished before its timeout came in
+    deadline.disarm();
+    let (state, action) = State::Idling.next(&Message::Timeout(current), &deadline);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::DoNothing));
+  }
+
+  #[test]
+  fn cancel_always_returns_to_idle() {
+    for busy in [State::Reading, State::Writing].iter() {
+      let (state, action) = busy.next(&Message::CancelTransaction, &IDLE);
+      assert_eq!(state, State::Idling);
+      assert!(matches!(action, Action::Abort(Error::Cancelled)));
+    }
+
+    // nobody to tell
+    let (state, action) = State::Idling.next(&Message::CancelTransaction, &IDLE);
+    assert_eq!(state, State::Idling);
+    assert!(matches!(action, Action::Reset));
+  }
+}
@@ -0,0 +1,291 @@
+use core::convert::Infallible;
+
+use embedded_hal::{
+  spi::FullDuplex,
+  digital::v2::OutputPin,
+};
+
+use crate::spi_drv::{
+  Read,
+  Write,
+  TX_BUFFER_SIZE,
+  RX_BUFFER_SIZE,
+};
+
+/// Byte by byte transfer engine. Each call sends at most one byte, the caller
+/// is expected to come back once the byte clocked in with it is available.
+pub struct Transfer {
+  tx_buffer: [u8; TX_BUFFER_SIZE],
+  rx_buffer: [u8; RX_BUFFER_SIZE],
+  transfer_len: u8,
+  bytes_transferred: u8,
+  // length of the last finished read
+  received: u8
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Progress {
+  InProgress,
+  Done,
+}
+
+
+impl Transfer {
+  pub const fn new() -> Self {
+    Transfer {
+      tx_buffer: [0; TX_BUFFER_SIZE],
+      rx_buffer: [0; RX_BUFFER_SIZE],
+      transfer_len: 0,
+      bytes_transferred: 0,
+      received: 0
+    }
+  }
+
+  pub fn bytes_transferred(&self) -> u8 {
+    self.bytes_transferred
+  }
+
+  /// Data of the last read, valid until the next transfer starts
+  pub fn received(&self) -> &[u8] {
+    &self.rx_buffer[..usize::from(self.received)]
+  }
+
+  pub fn start_read<B, C>(&mut self, bus: &mut B, cs: &mut C, r: Read) -> nb::Result<(), B::Error>
+    where B: FullDuplex<u8>, C: OutputPin<Error = Infallible>
+  {
+    cs.set_low().unwrap();
+    self.tx_buffer[0] = r.reg;
+    self.transfer_len = r.len.min(RX_BUFFER_SIZE as u8);
+    self.received = 0;
+    bus.send(self.tx_buffer[0])
+  }
+
+  pub fn start_write<B, C>(&mut self, bus: &mut B, cs: &mut C, w: Write) -> nb::Result<(), B::Error>
+    where B: FullDuplex<u8>, C: OutputPin<Error = Infallible>
+  {
+    cs.set_low().unwrap();
+    self.received = 0;
+
+    // the length includes the register byte
+    let len = usize::from(w.len).clamp(1, TX_BUFFER_SIZE);
+    self.tx_buffer[0] = w.reg;
+    self.tx_buffer[1..len].copy_from_slice(&w.data[..len - 1]);
+    self.transfer_len = len as u8;
+
+    bus.send(self.tx_buffer[0])
+  }
+
+  /// Handles the byte received during a read
+  pub fn continue_read<B, C>(&mut self, bus: &mut B, cs: &mut C) -> nb::Result<Progress, B::Error>
+    where B: FullDuplex<u8>, C: OutputPin<Error = Infallible>
+  {
+    let val = bus.read()?;
+
+    // the first byte is garbage data
+    if self.bytes_transferred > 0 {
+      self.rx_buffer[usize::from(self.bytes_transferred) - 1] = val;
+    }
+
+    if self.bytes_transferred == self.transfer_len {
+      cs.set_high().unwrap();
+      self.received = self.transfer_len;
+      self.reset();
+      Ok(Progress::Done)
+    } else {
+      // send 0x00 as dummy byte to keep transfer going
+      bus.send(0x00)?;
+      self.bytes_transferred += 1;
+      Ok(Progress::InProgress)
+    }
+  }
+
+  /// Handles the byte received during a write
+  pub fn continue_write<B, C>(&mut self, bus: &mut B, cs: &mut C) -> nb::Result<Progress, B::Error>
+    where B: FullDuplex<u8>, C: OutputPin<Error = Infallible>
+  {
+    // read out the value from spi module and ignore it
+    bus.read()?;
+    self.bytes_transferred += 1;
+
+    if self.bytes_transferred == self.transfer_len {
+      cs.set_high().unwrap();
+      self.reset();
+      Ok(Progress::Done)
+    } else {
+      bus.send(self.tx_buffer[usize::from(self.bytes_transferred)])?;
+      Ok(Progress::InProgress)
+    }
+  }
+
+  pub fn reset(&mut self) {
+    self.transfer_len = 0;
+    self.bytes_transferred = 0;
+  }
+}
+
+impl Default for Transfer {
+  fn default() -> Self {
+    Transfer::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lis3dsh::sim::Lis3dshSim;
+  use crate::spi_drv::Device;
+
+  fn read_all(transfer: &mut Transfer, sim: &Lis3dshSim, r: Read) -> usize {
+    let (mut bus, mut cs) = sim.split();
+    transfer.start_read(&mut bus, &mut cs, r).unwrap();
+
+    let mut events = 0;
+    loop {
+      events += 1;
+      if transfer.continue_read(&mut bus, &mut cs).unwrap() == Progress::Done {
+        return events;
+      }
+    }
+  }
+
+  fn write_all(transfer: &mut Transfer, sim: &Lis3dshSim, w: Write) -> usize {
+    let (mut bus, mut cs) = sim.split();
+    transfer.start_write(&mut bus, &mut cs, w).unwrap();
+
+    let mut events = 0;
+    loop {
+      events += 1;
+      if transfer.continue_write(&mut bus, &mut cs).unwrap() == Progress::Done {
+        return events;
+      }
+    }
+  }
+
+  #[test]
+  fn single_register_read() {
+    let sim = Lis3dshSim::new();
+    let mut transfer = Transfer::new();
+
+    let events = read_all(&mut transfer, &sim, Read { device: Device::Lis3dsh, reg: 0x8F, len: 1 });
+
+    // one event for the address byte and one for the data
+    assert_eq!(events, 2);
+    assert_eq!(transfer.received(), &[0x3F]);
+    assert_eq!(sim.mosi(), vec![0x8F, 0x00]);
+    assert!(!sim.is_selected());
+    assert_eq!(sim.transactions(), 1);
+  }
+
+  #[test]
+  fn garbage_byte_is_skipped_on_burst_reads() {
+    let sim = Lis3dshSim::new();
+    sim.set_registers(0x28, &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+    let mut transfer = Transfer::new();
+
+    let events = read_all(&mut transfer, &sim, Read { device: Device::Lis3dsh, reg: 0xA8, len: 6 });
+
+    assert_eq!(events, 7);
+    assert_eq!(transfer.received(), &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+    assert_eq!(sim.mosi(), vec![0xA8, 0, 0, 0, 0, 0, 0]);
+  }
+
+  #[test]
+  fn bytes_transferred_counts_data_bytes() {
+    let sim = Lis3dshSim::new();
+    let (mut bus, mut cs) = sim.split();
+    let mut transfer = Transfer::new();
+
+    transfer.start_read(&mut bus, &mut cs, Read { device: Device::Lis3dsh, reg: 0xA8, len: 2 }).unwrap();
+    assert_eq!(transfer.bytes_transferred(), 0);
+    assert!(sim.is_selected());
+
+    assert_eq!(transfer.continue_read(&mut bus, &mut cs), Ok(Progress::InProgress));
+    assert_eq!(transfer.bytes_transferred(), 1);
+    assert_eq!(transfer.continue_read(&mut bus, &mut cs), Ok(Progress::InProgress));
+    assert_eq!(transfer.bytes_transferred(), 2);
+    assert_eq!(transfer.continue_read(&mut bus, &mut cs), Ok(Progress::Done));
+
+    // finishing resets the bookkeeping for the next transaction
+    assert_eq!(transfer.bytes_transferred(), 0);
+    assert!(!sim.is_selected());
+  }
+
+  #[test]
+  fn register_write() {
+    let sim = Lis3dshSim::new();
+    let mut transfer = Transfer::new();
+    let mut data = [0; TX_BUFFER_SIZE];
+    data[0] = 0x67;
+
+    let events = write_all(&mut transfer, &sim, Write { device: Device::Lis3dsh, reg: 0x20, len: 2, data });
+
+    assert_eq!(events, 2);
+    assert_eq!(sim.register(0x20), 0x67);
+    assert_eq!(sim.mosi(), vec![0x20, 0x67]);
+    assert!(!sim.is_selected());
+  }
+
+  #[test]
+  fn burst_write_auto_increments() {
+    let sim = Lis3dshSim::new();
+    let mut transfer = Transfer::new();
+    let mut data = [0; TX_BUFFER_SIZE];
+    data[..3].copy_from_slice(&[0x11, 0x22, 0x33]);
+
+    write_all(&mut transfer, &sim, Write { device: Device::Lis3dsh, reg: 0x10, len: 4, data });
+
+    assert_eq!(sim.register(0x10), 0x11);
+    assert_eq!(sim.register(0x11), 0x22);
+    assert_eq!(sim.register(0x12), 0x33);
+  }
+
+  #[test]
+  fn write_then_read_back() {
+    let sim = Lis3dshSim::new();
+    let mut transfer = Transfer::new();
+    let mut data = [0; TX_BUFFER_SIZE];
+    data[0] = 0x5A;
+
+    write_all(&mut transfer, &sim, Write { device: Device::Lis3dsh, reg: 0x1F, len: 2, data });
+    read_all(&mut transfer, &sim, Read { device: Device::Lis3dsh, reg: 0x9F, len: 1 });
+
+    assert_eq!(transfer.received(), &[0x5A]);
+    assert_eq!(sim.transactions(), 2);
+  }
+
+  #[test]
+  fn reads_are_clamped_to_the_receive_buffer() {
+    let sim = Lis3dshSim::new();
+    let mut transfer = Transfer::new();
+
+    let events = read_all(&mut transfer, &sim, Read { device: Device::Lis3dsh, reg: 0x80, len: 200 });
+
+    assert_eq!(events, RX_BUFFER_SIZE + 1);
+    assert_eq!(transfer.received().len(), RX_BUFFER_SIZE);
+  }
+
+  #[test]
+  fn writes_leave_nothing_received() {
+    let sim = Lis3dshSim::new();
+    let mut transfer = Transfer::new();
+
+    read_all(&mut transfer, &sim, Read { device: Device::Lis3dsh, reg: 0x8F, len: 1 });
+    write_all(&mut transfer, &sim, Write { device: Device::Lis3dsh, reg: 0x1F, len: 2, data: [0; TX_BUFFER_SIZE] });
+
+    assert!(transfer.received().is_empty());
+  }
+
+  #[test]
+  fn reset_abandons_a_transfer() {
+    let sim = Lis3dshSim::new();
+    let (mut bus, mut cs) = sim.split();
+    let mut transfer = Transfer::new();
+
+    transfer.start_read(&mut bus, &mut cs, Read { device: Device::Lis3dsh, reg: 0xA8, len: 6 }).unwrap();
+    transfer.continue_read(&mut bus, &mut cs).unwrap();
+    transfer.reset();
+
+    assert_eq!(transfer.bytes_transferred(), 0);
+  }
+}
@@ -0,0 +1,115 @@
+//! Spans of time in the units people write them in, converted to and from
+//! cycles of the core clock. CYCCNT and the monotonic count in `u32` cycles,
+//! which at 168MHz lasts only about 25.5s, so every conversion that could
+//! overflow is checked. All of it is `const` so fixed delays are worked out
+//! at compile time.
+
+/// A span that can be turned into cycles of a `cpu_freq` Hz clock
+pub trait TimeUnit: Copy {
+  /// `None` when it does not fit the cycle counter
+  fn to_cycles(self, cpu_freq: u32) -> Option<u32>;
+}
+
+macro_rules! time_units {
+  ($($(#[$doc:meta])* $unit:ident = $per_second:expr;)*) => {
+    $(
+      $(#[$doc])*
+      #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+      pub struct $unit(pub u32);
+
+      impl $unit {
+        /// `None` when it does not fit the cycle counter. Rounds down to
+        /// whole cycles.
+        pub const fn to_cycles(self, cpu_freq: u32) -> Option<u32> {
+          let cycles = self.0 as u64 * cpu_freq as u64 / $per_second;
+          if cycles > u32::MAX as u64 {
+            None
+          } else {
+            Some(cycles as u32)
+          }
+        }
+
+        /// Rounds down to whole units, saturates on clocks too slow to count
+        /// `cycles` in a `u32` of them
+        pub const fn from_cycles(cycles: u32, cpu_freq: u32) -> Self {
+          let units = cycles as u64 * $per_second / cpu_freq as u64;
+          if units > u32::MAX as u64 {
+            $unit(u32::MAX)
+          } else {
+            $unit(units as u32)
+          }
+        }
+      }
+
+      impl TimeUnit for $unit {
+        fn to_cycles(self, cpu_freq: u32) -> Option<u32> {
+          $unit::to_cycles(self, cpu_freq)
+        }
+      }
+    )*
+  };
+}
+
+time_units! {
+  Micros = 1_000_000;
+  Millis = 1_000;
+  Seconds = 1;
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CPU_FREQ: u32 = 168_000_000;
+
+  #[test]
+  fn longest_spans_that_fit() {
+    assert_eq!(Micros(25_565_281).to_cycles(CPU_FREQ), Some(4_294_967_208));
+    assert_eq!(Micros(25_565_282).to_cycles(CPU_FREQ), None);
+
+    assert_eq!(Millis(25_565).to_cycles(CPU_FREQ), Some(4_294_920_000));
+    assert_eq!(Millis(25_566).to_cycles(CPU_FREQ), None);
+
+    assert_eq!(Seconds(25).to_cycles(CPU_FREQ), Some(4_200_000_000));
+    assert_eq!(Seconds(26).to_cycles(CPU_FREQ), None);
+    assert_eq!(Seconds(u32::MAX).to_cycles(CPU_FREQ), None);
+  }
+
+  #[test]
+  fn zero_is_zero() {
+    assert_eq!(Micros(0).to_cycles(CPU_FREQ), Some(0));
+    assert_eq!(Seconds::from_cycles(0, CPU_FREQ), Seconds(0));
+  }
+
+  #[test]
+  fn cycles_round_down() {
+    assert_eq!(Micros::from_cycles(167, CPU_FREQ), Micros(0));
+    assert_eq!(Micros::from_cycles(168, CPU_FREQ), Micros(1));
+    assert_eq!(Millis::from_cycles(335_999, CPU_FREQ), Millis(1));
+    assert_eq!(Micros::from_cycles(u32::MAX, CPU_FREQ), Micros(25_565_281));
+    assert_eq!(Seconds::from_cycles(u32::MAX, CPU_FREQ), Seconds(25));
+
+    // below a cycle per microsecond
+    assert_eq!(Micros(3).to_cycles(500_000), Some(1));
+  }
+
+  #[test]
+  fn slow_clocks_saturate() {
+    assert_eq!(Micros::from_cycles(u32::MAX, 1), Micros(u32::MAX));
+  }
+
+  #[test]
+  fn whole_units_survive_the_round_trip() {
+    for us in [1, 999, 30_000, 1_000_000, 25_565_281].iter() {
+      let cycles = Micros(*us).to_cycles(CPU_FREQ).unwrap();
+      assert_eq!(Micros::from_cycles(cycles, CPU_FREQ), Micros(*us));
+    }
+  }
+
+  #[test]
+  fn usable_in_constants() {
+    const PERIOD: Option<u32> = Millis(30).to_cycles(CPU_FREQ);
+    assert_eq!(PERIOD, Some(5_040_000));
+  }
+}
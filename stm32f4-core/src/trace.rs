@@ -0,0 +1,205 @@
+//! Ring buffer of the messages passed between tasks, dumped over the debug
+//! channel one record per line and decoded on the host.
+
+use core::fmt;
+
+/// Records kept before the oldest is overwritten
+pub const TRACE_SIZE: usize = 64;
+
+// every dumped line starts with this so the decoder can pick them out of the log
+const PREFIX: &str = "TRACE";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Event {
+  Send,
+  Schedule,
+  SpawnFailed,
+  ScheduleFailed,
+  // the destination has no mailbox
+  Undeliverable,
+}
+
+/// One routed message. Names are kept instead of ids so a dump reads on its own.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Record<'a> {
+  // CYCCNT when the message was routed
+  pub timestamp: u32,
+  pub event: Event,
+  pub source: &'a str,
+  pub dest: &'a str,
+  pub message: &'a str,
+  // the variant of a module's message, e.g. `StartRead` of `Spi`
+  pub variant: Option<&'a str>,
+}
+
+#[derive(Clone)]
+pub struct Trace {
+  records: [Option<Record<'static>>; TRACE_SIZE],
+  next: usize,
+  // records ever written, wraps
+  total: u32,
+}
+
+
+impl Event {
+  pub fn name(self) -> &'static str {
+    match self {
+      Event::Send => "send",
+      Event::Schedule => "schedule",
+      Event::SpawnFailed => "spawn-failed",
+      Event::ScheduleFailed => "schedule-failed",
+      Event::Undeliverable => "undeliverable",
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Event> {
+    match name {
+      "send" => Some(Event::Send),
+      "schedule" => Some(Event::Schedule),
+      "spawn-failed" => Some(Event::SpawnFailed),
+      "schedule-failed" => Some(Event::ScheduleFailed),
+      "undeliverable" => Some(Event::Undeliverable),
+      _ => None,
+    }
+  }
+
+  pub fn is_failure(self) -> bool {
+    !matches!(self, Event::Send | Event::Schedule)
+  }
+}
+
+/// The line format of a dump: `TRACE <timestamp> <event> <source> <dest> <message>[::<variant>]`
+impl fmt::Display for Record<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {} {} {} {} {}", PREFIX, self.timestamp, self.event.name(), self.source, self.dest, self.message)?;
+    match self.variant {
+      Some(variant) => write!(f, "::{}", variant),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Reads back a dumped line, anything else in the log gives `None`
+pub fn parse(line: &str) -> Option<Record<'_>> {
+  let mut fields = line.split_whitespace();
+  if fields.next()? != PREFIX {
+    return None;
+  }
+
+  let timestamp = fields.next()?.parse().ok()?;
+  let event = Event::from_name(fields.next()?)?;
+  let source = fields.next()?;
+  let dest = fields.next()?;
+  let label = fields.next()?;
+  let (message, variant) = match label.split_once("::") {
+    Some((message, variant)) => (message, Some(variant)),
+    None => (label, None),
+  };
+  let record = Record { timestamp, event, source, dest, message, variant };
+
+  match fields.next() {
+    None => Some(record),
+    Some(_) => None,
+  }
+}
+
+impl Trace {
+  pub const fn new() -> Self {
+    Trace {
+      records: [None; TRACE_SIZE],
+      next: 0,
+      total: 0,
+    }
+  }
+
+  pub fn record(&mut self, record: Record<'static>) {
+    self.records[self.next] = Some(record);
+    self.next = (self.next + 1) % TRACE_SIZE;
+    self.total = self.total.wrapping_add(1);
+  }
+
+  /// Oldest record first
+  pub fn iter(&self) -> impl Iterator<Item = &Record<'static>> {
+    let (newer, older) = self.records.split_at(self.next);
+    older.iter().chain(newer.iter()).filter_map(|r| r.as_ref())
+  }
+
+  pub fn len(&self) -> usize {
+    self.iter().count()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.total == 0
+  }
+
+  /// Records that were overwritten before they could be dumped
+  pub fn lost(&self) -> u32 {
+    self.total.saturating_sub(TRACE_SIZE as u32)
+  }
+}
+
+impl Default for Trace {
+  fn default() -> Self {
+    Trace::new()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn send(timestamp: u32) -> Record<'static> {
+    Record { timestamp, event: Event::Send, source: "Lis3dsh", dest: "Spi1", message: "Spi", variant: Some("StartRead") }
+  }
+
+  #[test]
+  fn records_come_out_oldest_first() {
+    let mut trace = Trace::new();
+    trace.record(send(1));
+    trace.record(send(2));
+
+    let timestamps: Vec<u32> = trace.iter().map(|r| r.timestamp).collect();
+    assert_eq!(timestamps, vec![1, 2]);
+    assert_eq!(trace.lost(), 0);
+  }
+
+  #[test]
+  fn oldest_records_are_overwritten() {
+    let mut trace = Trace::new();
+    for t in 0..(TRACE_SIZE as u32 + 3) {
+      trace.record(send(t));
+    }
+
+    assert_eq!(trace.len(), TRACE_SIZE);
+    assert_eq!(trace.iter().next().unwrap().timestamp, 3);
+    assert_eq!(trace.iter().last().unwrap().timestamp, TRACE_SIZE as u32 + 2);
+    assert_eq!(trace.lost(), 3);
+  }
+
+  #[test]
+  fn dumped_lines_parse_back() {
+    let record = Record { timestamp: 168_000, event: Event::SpawnFailed, source: "Interrupt", dest: "Spi1", message: "Spi", variant: Some("RxEvent") };
+    let line = record.to_string();
+
+    assert_eq!(line, "TRACE 168000 spawn-failed Interrupt Spi1 Spi::RxEvent");
+    assert_eq!(parse(&line), Some(record));
+  }
+
+  #[test]
+  fn messages_without_a_variant_parse_back() {
+    let record = Record { timestamp: 0, event: Event::Send, source: "Lis3dsh", dest: "Init", message: "DeviceId", variant: None };
+    let line = record.to_string();
+
+    assert_eq!(line, "TRACE 0 send Lis3dsh Init DeviceId");
+    assert_eq!(parse(&line), Some(record));
+  }
+
+  #[test]
+  fn other_log_lines_are_skipped() {
+    assert_eq!(parse("X-axis: 0.98"), None);
+    assert_eq!(parse("TRACE 12 teleport Init Spi1 Spi"), None);
+    assert_eq!(parse("TRACE 12 send Init Spi1"), None);
+    assert_eq!(parse("TRACE 12 send Init Spi1 Spi extra"), None);
+  }
+}